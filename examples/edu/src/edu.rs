@@ -0,0 +1,274 @@
+use std::rc::Rc;
+
+use anyhow::{ensure, Result};
+
+use libvfio_user::{Device, DeviceContext, DeviceResetReason};
+
+// Register offsets inside BAR0, see docs/specs/edu.rst in the QEMU tree
+const REG_IDENTIFICATION: usize = 0x00;
+const REG_LIVENESS: usize = 0x04;
+const REG_FACTORIAL: usize = 0x08;
+const REG_STATUS: usize = 0x20;
+const REG_IRQ_STATUS: usize = 0x24;
+const REG_IRQ_RAISE: usize = 0x60;
+const REG_IRQ_ACK: usize = 0x64;
+const REG_DMA_SOURCE: usize = 0x80;
+const REG_DMA_DESTINATION: usize = 0x88;
+const REG_DMA_COUNT: usize = 0x90;
+const REG_DMA_COMMAND: usize = 0x98;
+
+// Major version 1, minor version 0
+const IDENTIFICATION: u32 = 0x010000ed;
+
+const STATUS_COMPUTING: u32 = 0x01;
+const STATUS_IRQ_FACTORIAL: u32 = 0x80;
+
+const IRQ_FACTORIAL: u32 = 0x001;
+const IRQ_DMA: u32 = 0x100;
+
+const DMA_RUN: u64 = 0x1;
+const DMA_DIRECTION_TO_RAM: u64 = 0x2;
+const DMA_IRQ: u64 = 0x4;
+
+// The DMA buffer is only reachable through the DMA engine, at this address
+const DMA_START: u64 = 0x40000;
+const DMA_SIZE: usize = 4096;
+
+// Default DMA mask of the device, addresses are clamped to 28 bits
+const DMA_MASK: u64 = (1 << 28) - 1;
+
+// Guest RAM backing a single transfer may be split into several DMA regions
+const MAX_DMA_REGIONS: usize = 4;
+
+pub const BAR0_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Default)]
+struct DmaState {
+    source: u64,
+    destination: u64,
+    count: u64,
+    command: u64,
+}
+
+/// Clone of QEMU's educational "edu" PCI device
+pub struct EduDevice {
+    ctx: Rc<DeviceContext>,
+
+    liveness: u32,
+    factorial: u32,
+    status: u32,
+    irq_status: u32,
+
+    dma: DmaState,
+    dma_buffer: [u8; DMA_SIZE],
+}
+
+impl EduDevice {
+    pub fn context(&self) -> &DeviceContext {
+        &self.ctx
+    }
+
+    fn raise_irq(&mut self, value: u32) {
+        self.irq_status |= value;
+
+        if self.irq_status != 0 {
+            if let Err(e) = self.ctx.trigger_irq(0) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    fn lower_irq(&mut self, value: u32) {
+        // Interrupts are delivered as edges via eventfd, there is no line to lower
+        self.irq_status &= !value;
+    }
+
+    fn compute_factorial(&mut self, value: u32) {
+        // Computed synchronously, so the computing bit is never observed by the driver
+        self.status |= STATUS_COMPUTING;
+        self.factorial = (1..=value).fold(1u32, |acc, i| acc.wrapping_mul(i));
+        self.status &= !STATUS_COMPUTING;
+
+        if self.status & STATUS_IRQ_FACTORIAL != 0 {
+            self.raise_irq(IRQ_FACTORIAL);
+        }
+    }
+
+    /// Offset of a transfer inside the DMA buffer, the whole transfer must fit into the buffer
+    fn buffer_offset(address: u64, count: u64) -> Result<usize> {
+        let end = address.checked_add(count);
+        ensure!(
+            address >= DMA_START && end.is_some_and(|end| end <= DMA_START + DMA_SIZE as u64),
+            "EDU: DMA range {:#x}-{:#x} out of bounds ({:#x}-{:#x})",
+            address,
+            address.wrapping_add(count),
+            DMA_START,
+            DMA_START + DMA_SIZE as u64
+        );
+        Ok((address - DMA_START) as usize)
+    }
+
+    fn clamp_address(address: u64) -> usize {
+        let clamped = address & DMA_MASK;
+        if clamped != address {
+            eprintln!("EDU: clamping DMA address {:#x} to {:#x}", address, clamped);
+        }
+        clamped as usize
+    }
+
+    fn run_dma(&mut self) -> Result<()> {
+        let count = self.dma.count as usize;
+        if count == 0 {
+            return Ok(());
+        }
+
+        if self.dma.command & DMA_DIRECTION_TO_RAM == 0 {
+            // RAM -> buffer
+            let offset = Self::buffer_offset(self.dma.destination, self.dma.count)?;
+            let address = Self::clamp_address(self.dma.source);
+            let mapping = self
+                .ctx
+                .dma_map(address, count, MAX_DMA_REGIONS, true, false)?;
+
+            let mut buffer = &mut self.dma_buffer[offset..offset + count];
+            for region in 0..mapping.base_addresses().len() {
+                let (chunk, rest) = buffer.split_at_mut(mapping.region_length(region));
+                mapping.read_into_volatile(region, chunk, 0)?;
+                buffer = rest;
+            }
+        } else {
+            // Buffer -> RAM
+            let offset = Self::buffer_offset(self.dma.source, self.dma.count)?;
+            let address = Self::clamp_address(self.dma.destination);
+            let mapping = self
+                .ctx
+                .dma_map(address, count, MAX_DMA_REGIONS, false, true)?;
+
+            let mut buffer = &self.dma_buffer[offset..offset + count];
+            for region in 0..mapping.base_addresses().len() {
+                let (chunk, rest) = buffer.split_at(mapping.region_length(region));
+                mapping.write_volatile(region, chunk, 0)?;
+                buffer = rest;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn start_dma(&mut self, command: u64) {
+        self.dma.command = command;
+        if command & DMA_RUN == 0 {
+            return;
+        }
+
+        // QEMU runs the transfer from a timer, finishing it right away is indistinguishable
+        // for a driver waiting for the run bit to clear or the interrupt to arrive
+        if let Err(e) = self.run_dma() {
+            eprintln!("EDU: DMA transfer failed: {}", e);
+        }
+
+        self.dma.command &= !DMA_RUN;
+        if self.dma.command & DMA_IRQ != 0 {
+            self.raise_irq(IRQ_DMA);
+        }
+    }
+
+    fn read_register(&self, offset: usize) -> Option<u64> {
+        let value = match offset {
+            REG_IDENTIFICATION => IDENTIFICATION as u64,
+            REG_LIVENESS => self.liveness as u64,
+            REG_FACTORIAL => self.factorial as u64,
+            REG_STATUS => self.status as u64,
+            REG_IRQ_STATUS => self.irq_status as u64,
+            REG_DMA_SOURCE => self.dma.source,
+            REG_DMA_DESTINATION => self.dma.destination,
+            REG_DMA_COUNT => self.dma.count,
+            REG_DMA_COMMAND => self.dma.command,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn write_register(&mut self, offset: usize, value: u64) {
+        match offset {
+            REG_LIVENESS => self.liveness = !(value as u32),
+            REG_FACTORIAL if self.status & STATUS_COMPUTING == 0 => {
+                self.compute_factorial(value as u32)
+            }
+            REG_STATUS => {
+                if value as u32 & STATUS_IRQ_FACTORIAL != 0 {
+                    self.status |= STATUS_IRQ_FACTORIAL;
+                } else {
+                    self.status &= !STATUS_IRQ_FACTORIAL;
+                }
+            }
+            REG_IRQ_RAISE => self.raise_irq(value as u32),
+            REG_IRQ_ACK => self.lower_irq(value as u32),
+            REG_DMA_SOURCE => self.dma.source = value,
+            REG_DMA_DESTINATION => self.dma.destination = value,
+            REG_DMA_COUNT => self.dma.count = value,
+            // Commands are ignored while a transfer is running
+            REG_DMA_COMMAND if self.dma.command & DMA_RUN == 0 => self.start_dma(value),
+            _ => {}
+        }
+    }
+}
+
+impl Device for EduDevice {
+    fn new(ctx: Rc<DeviceContext>) -> Self {
+        EduDevice {
+            ctx,
+            liveness: 0,
+            factorial: 0,
+            status: 0,
+            irq_status: 0,
+            dma: DmaState::default(),
+            dma_buffer: [0; DMA_SIZE],
+        }
+    }
+
+    fn log(&self, level: i32, msg: &str) {
+        eprintln!("[{}] {}", level, msg);
+    }
+
+    fn reset(&mut self, _reason: DeviceResetReason) -> Result<(), i32> {
+        self.liveness = 0;
+        self.factorial = 0;
+        self.status = 0;
+        self.irq_status = 0;
+        self.dma = DmaState::default();
+        Ok(())
+    }
+
+    fn region_access_bar0(
+        &mut self, offset: usize, data: &mut [u8], write: bool,
+    ) -> Result<usize, i32> {
+        // Like the original, registers below 0x80 only accept 32-bit accesses and the DMA
+        // registers 32 or 64-bit accesses, anything else reads all ones and ignores writes
+        let size = data.len();
+        let valid_size = if offset < REG_DMA_SOURCE {
+            size == 4
+        } else {
+            size == 4 || size == 8
+        };
+
+        if write {
+            if valid_size {
+                let mut bytes = [0u8; 8];
+                bytes[..size].copy_from_slice(data);
+                self.write_register(offset, u64::from_le_bytes(bytes));
+            }
+        } else {
+            let value = match valid_size {
+                true => self.read_register(offset).unwrap_or(u64::MAX),
+                false => u64::MAX,
+            };
+            let bytes = value.to_le_bytes();
+            let len = size.min(bytes.len());
+            data[..len].copy_from_slice(&bytes[..len]);
+            data[len..].fill(0xff);
+        }
+
+        Ok(size)
+    }
+}
@@ -0,0 +1,59 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use libvfio_user::{
+    DeviceConfigurator, DeviceRegion, DeviceRegionKind, InterruptRequestKind, PciConfig, PciType,
+};
+
+use crate::edu::{EduDevice, BAR0_SIZE};
+
+mod edu;
+
+fn main() -> Result<()> {
+    let socket_path: PathBuf = env::args()
+        .nth(1)
+        .context("Usage: edu <socket path>")?
+        .into();
+
+    let config = DeviceConfigurator::default()
+        .socket_path(socket_path.clone())
+        .overwrite_socket(true)
+        .pci_type(PciType::Pci)
+        .pci_config(PciConfig {
+            vendor_id: 0x1234,
+            device_id: 0x11e8,
+            subsystem_vendor_id: 0x1af4,
+            subsystem_id: 0x1100,
+            class_code_base: 0xff,
+            class_code_subclass: 0x00,
+            class_code_programming_interface: 0x00,
+            revision_id: 0x10,
+        })
+        .add_device_region(DeviceRegion {
+            region_type: DeviceRegionKind::Bar0,
+            size: BAR0_SIZE,
+            file: None,
+            offset: 0,
+            read: true,
+            write: true,
+            memory: true,
+            shared_memory: None,
+        })
+        .using_interrupt_requests(InterruptRequestKind::IntX, 1)
+        .using_interrupt_requests(InterruptRequestKind::Msi, 1)
+        .setup_dma(true)
+        .build()?;
+
+    let device = config.produce::<EduDevice>()?;
+    let ctx = device.context();
+
+    eprintln!("Waiting for client on {}", socket_path.display());
+    ctx.attach()?;
+
+    // Blocking, returns once the client disconnects
+    ctx.run()?;
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+#![no_main]
+
+use std::cell::RefCell;
+use std::env;
+
+use libfuzzer_sys::fuzz_target;
+
+use libvfio_user::fuzz::{FuzzHarness, FuzzOperation};
+use libvfio_user::{
+    DeviceConfiguration, DeviceConfigurator, DeviceRegion, DeviceRegionKind, InterruptRequestKind,
+    PciConfig,
+};
+
+use crate::edu::{EduDevice, BAR0_SIZE};
+
+// Fuzz the device of the edu example as is
+#[allow(dead_code)]
+#[path = "../../examples/edu/src/edu.rs"]
+mod edu;
+
+thread_local! {
+    // Set up once, every input ends with a disconnect which resets the device
+    static HARNESS: RefCell<FuzzHarness<EduDevice>> =
+        RefCell::new(FuzzHarness::new(&configuration()).unwrap());
+}
+
+fn configuration() -> DeviceConfiguration {
+    // The socket is created but never connected to
+    let socket_path = env::temp_dir().join(format!("edu-fuzz-{}.sock", std::process::id()));
+
+    DeviceConfigurator::default()
+        .socket_path(socket_path)
+        .overwrite_socket(true)
+        .pci_config(PciConfig {
+            vendor_id: 0x1234,
+            device_id: 0x11e8,
+            subsystem_vendor_id: 0x1af4,
+            subsystem_id: 0x1100,
+            class_code_base: 0xff,
+            class_code_subclass: 0x00,
+            class_code_programming_interface: 0x00,
+            revision_id: 0x10,
+        })
+        .add_device_region(DeviceRegion {
+            region_type: DeviceRegionKind::Bar0,
+            size: BAR0_SIZE,
+            file: None,
+            offset: 0,
+            read: true,
+            write: true,
+            memory: true,
+            shared_memory: None,
+        })
+        .using_interrupt_requests(InterruptRequestKind::IntX, 1)
+        .using_interrupt_requests(InterruptRequestKind::Msi, 1)
+        .setup_dma(true)
+        .build()
+        .unwrap()
+}
+
+fuzz_target!(|operations: Vec<FuzzOperation>| {
+    HARNESS.with_borrow_mut(|harness| harness.run(&operations));
+});
@@ -0,0 +1,13 @@
+// dma.h fnv1a64: unverified
+/* Transcribed from struct dma_sg in lib/dma.h of libvfio-user, see libvfio-user.rs. The wrapper
+ * checks its size against dma_sg_size() before use. */
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct dma_sg {
+    pub dma_addr: vfu_dma_addr_t,
+    pub region: ::std::os::raw::c_int,
+    pub length: u64,
+    pub offset: u64,
+    pub writeable: bool,
+}
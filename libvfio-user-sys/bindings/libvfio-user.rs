@@ -0,0 +1,401 @@
+// libvfio-user.h fnv1a64: unverified
+/* Transcribed from the public libvfio-user API in the layout bindgen 0.69 emits, since neither the
+ * submodule nor libclang were available. Regenerate with LIBVFIO_USER_UPDATE_BINDINGS=1 to replace
+ * this file and record the header hash, see README.md. Bit fields of the PCI header are only
+ * available through their raw members. */
+
+pub const LIBVFIO_USER_FLAG_ATTACH_NB: u32 = 1;
+pub const VFU_REGION_FLAG_READ: u32 = 1;
+pub const VFU_REGION_FLAG_WRITE: u32 = 2;
+pub const VFU_REGION_FLAG_RW: u32 = 3;
+pub const VFU_REGION_FLAG_MEM: u32 = 4;
+pub const VFU_REGION_FLAG_ALWAYS_CB: u32 = 8;
+pub const VFU_REGION_FLAG_MASK: u32 = 15;
+pub type __off64_t = ::std::os::raw::c_long;
+pub type __loff_t = __off64_t;
+pub type loff_t = __loff_t;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct iovec {
+    pub iov_base: *mut ::std::os::raw::c_void,
+    pub iov_len: usize,
+}
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union vfu_pci_hdr_id_t {
+    pub raw: u32,
+    pub __bindgen_anon_1: vfu_pci_hdr_id_t__bindgen_ty_1,
+}
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct vfu_pci_hdr_id_t__bindgen_ty_1 {
+    pub vid: u16,
+    pub did: u16,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub union vfu_pci_hdr_cmd_t {
+    pub raw: u16,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub union vfu_pci_hdr_sts_t {
+    pub raw: u16,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub union vfu_pci_hdr_cc_t {
+    pub raw: [u8; 3usize],
+    pub __bindgen_anon_1: vfu_pci_hdr_cc_t__bindgen_ty_1,
+}
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct vfu_pci_hdr_cc_t__bindgen_ty_1 {
+    pub pi: u8,
+    pub scc: u8,
+    pub bcc: u8,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub union vfu_pci_hdr_htype_t {
+    pub raw: u8,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub union vfu_bar_t {
+    pub raw: u32,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub union vfu_pci_hdr_ss_t {
+    pub raw: u32,
+    pub __bindgen_anon_1: vfu_pci_hdr_ss_t__bindgen_ty_1,
+}
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct vfu_pci_hdr_ss_t__bindgen_ty_1 {
+    pub vid: u16,
+    pub sid: u16,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub union vfu_pci_hdr_intr_t {
+    pub raw: u16,
+    pub __bindgen_anon_1: vfu_pci_hdr_intr_t__bindgen_ty_1,
+}
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct vfu_pci_hdr_intr_t__bindgen_ty_1 {
+    pub iline: u8,
+    pub ipin: u8,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub union vfu_pci_hdr_t {
+    pub raw: [u8; 64usize],
+    pub __bindgen_anon_1: vfu_pci_hdr_t__bindgen_ty_1,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct vfu_pci_hdr_t__bindgen_ty_1 {
+    pub id: vfu_pci_hdr_id_t,
+    pub cmd: vfu_pci_hdr_cmd_t,
+    pub sts: vfu_pci_hdr_sts_t,
+    pub rid: u8,
+    pub cc: vfu_pci_hdr_cc_t,
+    pub cls: u8,
+    pub mlt: u8,
+    pub htype: vfu_pci_hdr_htype_t,
+    pub bist: u8,
+    pub bars: [vfu_bar_t; 6usize],
+    pub ccptr: u32,
+    pub ss: vfu_pci_hdr_ss_t,
+    pub erom: u32,
+    pub cap: u8,
+    pub res1: [u8; 7usize],
+    pub intr: vfu_pci_hdr_intr_t,
+    pub mgnt: u8,
+    pub mlat: u8,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub union vfu_pci_config_space_t {
+    pub raw: [u8; 4096usize],
+    pub __bindgen_anon_1: vfu_pci_config_space_t__bindgen_ty_1,
+}
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct vfu_pci_config_space_t__bindgen_ty_1 {
+    pub hdr: vfu_pci_hdr_t,
+    pub non_std: [u8; 4032usize],
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vfu_ctx {
+    _unused: [u8; 0],
+}
+#[doc = " Opaque context of a libvfio-user device"]
+pub type vfu_ctx_t = vfu_ctx;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct dma_sg {
+    _unused: [u8; 0],
+}
+#[doc = " Scatter-gather entry, allocate dma_sg_size() bytes per entry"]
+pub type dma_sg_t = dma_sg;
+pub type vfu_dma_addr_t = *mut ::std::os::raw::c_void;
+pub const vfu_trans_t_VFU_TRANS_SOCK: vfu_trans_t = 0;
+pub const vfu_trans_t_VFU_TRANS_MAX: vfu_trans_t = 1;
+pub type vfu_trans_t = ::std::os::raw::c_uint;
+pub const vfu_dev_type_t_VFU_DEV_TYPE_PCI: vfu_dev_type_t = 0;
+pub type vfu_dev_type_t = ::std::os::raw::c_uint;
+extern "C" {
+    #[doc = " Create a context for a device served over the given transport, path is the socket\n path for VFU_TRANS_SOCK. Returns NULL and sets errno on failure."]
+    pub fn vfu_create_ctx(
+        trans: vfu_trans_t,
+        path: *const ::std::os::raw::c_char,
+        flags: ::std::os::raw::c_int,
+        pvt: *mut ::std::os::raw::c_void,
+        dev_type: vfu_dev_type_t,
+    ) -> *mut vfu_ctx_t;
+}
+extern "C" {
+    #[doc = " Finalize the device configuration, must be called before vfu_attach_ctx()."]
+    pub fn vfu_realize_ctx(vfu_ctx: *mut vfu_ctx_t) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    #[doc = " File descriptor to poll for client connections and messages."]
+    pub fn vfu_get_poll_fd(vfu_ctx: *mut vfu_ctx_t) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    #[doc = " Wait for a client to connect, returns -1 with errno EAGAIN in non-blocking mode\n when no client is pending."]
+    pub fn vfu_attach_ctx(vfu_ctx: *mut vfu_ctx_t) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    #[doc = " Process client messages, returns the number of messages handled or -1 with errno\n ENOTCONN once the client disconnected."]
+    pub fn vfu_run_ctx(vfu_ctx: *mut vfu_ctx_t) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn vfu_destroy_ctx(vfu_ctx: *mut vfu_ctx_t);
+}
+extern "C" {
+    #[doc = " The private pointer passed to vfu_create_ctx()."]
+    pub fn vfu_get_private(vfu_ctx: *mut vfu_ctx_t) -> *mut ::std::os::raw::c_void;
+}
+pub type vfu_log_fn_t = ::std::option::Option<
+    unsafe extern "C" fn(
+        vfu_ctx: *mut vfu_ctx_t,
+        level: ::std::os::raw::c_int,
+        msg: *const ::std::os::raw::c_char,
+    ),
+>;
+extern "C" {
+    #[doc = " Log messages of at most the given syslog level through the callback."]
+    pub fn vfu_setup_log(
+        vfu_ctx: *mut vfu_ctx_t,
+        log: vfu_log_fn_t,
+        level: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+}
+pub type vfu_region_access_cb_t = ::std::option::Option<
+    unsafe extern "C" fn(
+        vfu_ctx: *mut vfu_ctx_t,
+        buf: *mut ::std::os::raw::c_char,
+        count: usize,
+        offset: loff_t,
+        is_write: bool,
+    ) -> isize,
+>;
+pub const VFU_PCI_DEV_BAR0_REGION_IDX: _bindgen_ty_1 = 0;
+pub const VFU_PCI_DEV_BAR1_REGION_IDX: _bindgen_ty_1 = 1;
+pub const VFU_PCI_DEV_BAR2_REGION_IDX: _bindgen_ty_1 = 2;
+pub const VFU_PCI_DEV_BAR3_REGION_IDX: _bindgen_ty_1 = 3;
+pub const VFU_PCI_DEV_BAR4_REGION_IDX: _bindgen_ty_1 = 4;
+pub const VFU_PCI_DEV_BAR5_REGION_IDX: _bindgen_ty_1 = 5;
+pub const VFU_PCI_DEV_ROM_REGION_IDX: _bindgen_ty_1 = 6;
+pub const VFU_PCI_DEV_CFG_REGION_IDX: _bindgen_ty_1 = 7;
+pub const VFU_PCI_DEV_VGA_REGION_IDX: _bindgen_ty_1 = 8;
+pub const VFU_PCI_DEV_MIGR_REGION_IDX: _bindgen_ty_1 = 9;
+pub const VFU_PCI_DEV_NUM_REGIONS: _bindgen_ty_1 = 10;
+pub type _bindgen_ty_1 = ::std::os::raw::c_uint;
+extern "C" {
+    #[doc = " Set up a device region. Accesses are passed to region_access unless the region is\n mappable through fd at offset, in which case the client may access mmap_areas directly."]
+    pub fn vfu_setup_region(
+        vfu_ctx: *mut vfu_ctx_t,
+        region_idx: ::std::os::raw::c_int,
+        size: usize,
+        region_access: vfu_region_access_cb_t,
+        flags: ::std::os::raw::c_int,
+        mmap_areas: *mut iovec,
+        nr_mmap_areas: u32,
+        fd: ::std::os::raw::c_int,
+        offset: u64,
+    ) -> ::std::os::raw::c_int;
+}
+pub const vfu_reset_type_VFU_RESET_DEVICE: vfu_reset_type = 0;
+pub const vfu_reset_type_VFU_RESET_LOST_CONN: vfu_reset_type = 1;
+pub const vfu_reset_type_VFU_RESET_PCI_FLR: vfu_reset_type = 2;
+pub type vfu_reset_type = ::std::os::raw::c_uint;
+pub use self::vfu_reset_type as vfu_reset_type_t;
+pub type vfu_reset_cb_t = ::std::option::Option<
+    unsafe extern "C" fn(vfu_ctx: *mut vfu_ctx_t, type_: vfu_reset_type_t) -> ::std::os::raw::c_int,
+>;
+extern "C" {
+    pub fn vfu_setup_device_reset_cb(
+        vfu_ctx: *mut vfu_ctx_t,
+        reset: vfu_reset_cb_t,
+    ) -> ::std::os::raw::c_int;
+}
+pub type vfu_device_quiesce_cb_t = ::std::option::Option<
+    unsafe extern "C" fn(vfu_ctx: *mut vfu_ctx_t) -> ::std::os::raw::c_int,
+>;
+extern "C" {
+    #[doc = " Called before the device state changes, return -1 with errno EBUSY and call\n vfu_device_quiesced() later to quiesce asynchronously."]
+    pub fn vfu_setup_device_quiesce_cb(
+        vfu_ctx: *mut vfu_ctx_t,
+        quiesce_cb: vfu_device_quiesce_cb_t,
+    );
+}
+extern "C" {
+    pub fn vfu_device_quiesced(vfu_ctx: *mut vfu_ctx_t, quiesce_errno: ::std::os::raw::c_int);
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vfu_dma_info {
+    pub iova: iovec,
+    pub vaddr: *mut ::std::os::raw::c_void,
+    pub mapping: iovec,
+    pub page_size: usize,
+    pub prot: u32,
+}
+pub type vfu_dma_info_t = vfu_dma_info;
+pub type vfu_dma_register_cb_t =
+    ::std::option::Option<unsafe extern "C" fn(vfu_ctx: *mut vfu_ctx_t, info: *mut vfu_dma_info_t)>;
+pub type vfu_dma_unregister_cb_t =
+    ::std::option::Option<unsafe extern "C" fn(vfu_ctx: *mut vfu_ctx_t, info: *mut vfu_dma_info_t)>;
+extern "C" {
+    #[doc = " Enable DMA, the callbacks are informed about regions the client adds or removes."]
+    pub fn vfu_setup_device_dma(
+        vfu_ctx: *mut vfu_ctx_t,
+        dma_register: vfu_dma_register_cb_t,
+        dma_unregister: vfu_dma_unregister_cb_t,
+    ) -> ::std::os::raw::c_int;
+}
+pub const vfu_dev_irq_type_VFU_DEV_INTX_IRQ: vfu_dev_irq_type = 0;
+pub const vfu_dev_irq_type_VFU_DEV_MSI_IRQ: vfu_dev_irq_type = 1;
+pub const vfu_dev_irq_type_VFU_DEV_MSIX_IRQ: vfu_dev_irq_type = 2;
+pub const vfu_dev_irq_type_VFU_DEV_ERR_IRQ: vfu_dev_irq_type = 3;
+pub const vfu_dev_irq_type_VFU_DEV_REQ_IRQ: vfu_dev_irq_type = 4;
+pub const vfu_dev_irq_type_VFU_DEV_NUM_IRQS: vfu_dev_irq_type = 5;
+pub type vfu_dev_irq_type = ::std::os::raw::c_uint;
+extern "C" {
+    pub fn vfu_setup_device_nr_irqs(
+        vfu_ctx: *mut vfu_ctx_t,
+        type_: vfu_dev_irq_type,
+        count: u32,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    #[doc = " Signal the eventfd the client registered for the given interrupt vector."]
+    pub fn vfu_irq_trigger(vfu_ctx: *mut vfu_ctx_t, subindex: u32) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    #[doc = " Size of a dma_sg_t, which is opaque to users of the library."]
+    pub fn dma_sg_size() -> usize;
+}
+extern "C" {
+    #[doc = " Translate a DMA range into at most max_nr_sgs entries. Returns the number of\n entries used, or -1 with errno set; if max_nr_sgs is too small the return value is\n -(needed + 1)."]
+    pub fn vfu_addr_to_sgl(
+        vfu_ctx: *mut vfu_ctx_t,
+        dma_addr: vfu_dma_addr_t,
+        len: usize,
+        sgl: *mut dma_sg_t,
+        max_nr_sgs: usize,
+        prot: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    #[doc = " Map the entries into the address space of the process, only possible for regions\n the client shared a file descriptor for."]
+    pub fn vfu_sgl_get(
+        vfu_ctx: *mut vfu_ctx_t,
+        sgl: *mut dma_sg_t,
+        iov: *mut iovec,
+        cnt: usize,
+        flags: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn vfu_sgl_mark_dirty(vfu_ctx: *mut vfu_ctx_t, sgl: *mut dma_sg_t, cnt: usize);
+}
+extern "C" {
+    pub fn vfu_sgl_put(vfu_ctx: *mut vfu_ctx_t, sgl: *mut dma_sg_t, iov: *mut iovec, cnt: usize);
+}
+extern "C" {
+    #[doc = " Read the entries through the client, for regions which are not mappable."]
+    pub fn vfu_sgl_read(
+        vfu_ctx: *mut vfu_ctx_t,
+        sgl: *mut dma_sg_t,
+        cnt: usize,
+        data: *mut ::std::os::raw::c_void,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    #[doc = " Write the entries through the client, for regions which are not mappable."]
+    pub fn vfu_sgl_write(
+        vfu_ctx: *mut vfu_ctx_t,
+        sgl: *mut dma_sg_t,
+        cnt: usize,
+        data: *mut ::std::os::raw::c_void,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn vfu_sg_is_mappable(vfu_ctx: *mut vfu_ctx_t, sg: *mut dma_sg_t) -> bool;
+}
+pub const vfu_pci_type_t_VFU_PCI_TYPE_CONVENTIONAL: vfu_pci_type_t = 0;
+pub const vfu_pci_type_t_VFU_PCI_TYPE_PCI_X_1: vfu_pci_type_t = 1;
+pub const vfu_pci_type_t_VFU_PCI_TYPE_PCI_X_2: vfu_pci_type_t = 2;
+pub const vfu_pci_type_t_VFU_PCI_TYPE_EXPRESS: vfu_pci_type_t = 3;
+pub type vfu_pci_type_t = ::std::os::raw::c_uint;
+extern "C" {
+    #[doc = " Initialize the config space of a PCI device of the given type and header type."]
+    pub fn vfu_pci_init(
+        vfu_ctx: *mut vfu_ctx_t,
+        pci_type: vfu_pci_type_t,
+        hdr_type: ::std::os::raw::c_int,
+        revision: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn vfu_pci_set_id(vfu_ctx: *mut vfu_ctx_t, vid: u16, did: u16, ssvid: u16, ssid: u16);
+}
+extern "C" {
+    pub fn vfu_pci_set_class(vfu_ctx: *mut vfu_ctx_t, base: u8, sub: u8, pi: u8);
+}
+extern "C" {
+    #[doc = " The config space of the device, valid after vfu_pci_init()."]
+    pub fn vfu_pci_get_config_space(vfu_ctx: *mut vfu_ctx_t) -> *mut vfu_pci_config_space_t;
+}
+extern "C" {
+    #[doc = " Add a capability at pos, or after the last capability if pos is 0. Returns the\n offset of the capability or -1 with errno set."]
+    pub fn vfu_pci_add_capability(
+        vfu_ctx: *mut vfu_ctx_t,
+        pos: usize,
+        flags: ::std::os::raw::c_int,
+        data: *mut ::std::os::raw::c_void,
+    ) -> isize;
+}
+extern "C" {
+    pub fn vfu_pci_find_capability(
+        vfu_ctx: *mut vfu_ctx_t,
+        extended: bool,
+        cap_id: ::std::os::raw::c_int,
+    ) -> usize;
+}
+extern "C" {
+    pub fn vfu_pci_find_next_capability(
+        vfu_ctx: *mut vfu_ctx_t,
+        extended: bool,
+        pos: usize,
+        cap_id: ::std::os::raw::c_int,
+    ) -> usize;
+}
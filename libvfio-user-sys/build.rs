@@ -1,51 +1,427 @@
+#[cfg(any(feature = "build-static", feature = "build-shared"))]
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::{env, fs};
 
-use anyhow::Result;
-use diffy::{apply, Patch};
+use anyhow::{anyhow, ensure, Result};
+#[cfg(any(feature = "build-static", feature = "build-shared"))]
 use meson_next::config::Config;
 
-fn patch(patch_path: &Path, target_path: &Path, keyword: &str) -> Result<()> {
+// Functions the wrapper uses which older releases lack, the scatter-gather API was renamed from
+// vfu_map_sg and friends, checked in the header of the system library instead
+const SYSTEM_REQUIRED_API: &[&str] = &[
+    "dma_sg_size",
+    "vfu_addr_to_sgl",
+    "vfu_sgl_get",
+    "vfu_sgl_put",
+    "vfu_sgl_mark_dirty",
+    "vfu_sgl_read",
+    "vfu_sgl_write",
+];
+
+// Bindings for the pinned submodule, used unless feature bindgen is enabled
+const PREGENERATED_BINDINGS: &str = "bindings/libvfio-user.rs";
+const PREGENERATED_DMA_SG: &str = "bindings/dma_sg.rs";
+// Set while building with feature bindgen to update the pre-generated bindings
+const UPDATE_BINDINGS_ENV: &str = "LIBVFIO_USER_UPDATE_BINDINGS";
+
+// Marks checked-in bindings which were not generated from the header, accepted with a warning
+const UNVERIFIED_HASH: &str = "unverified";
+
+// Environment variable choosing the maximum number of dma regions a client may register
+const DMA_LIMIT_ENV: &str = "LIBVFIO_USER_MAX_DMA_REGIONS";
+// MAX_DMA_REGIONS of upstream libvfio-user
+const DEFAULT_DMA_LIMIT: usize = 16;
+// MAX_DMA_REGIONS with feature patch-dma-limit, enough for fragmented guest memory maps
+const PATCHED_DMA_LIMIT: usize = 8192;
+// Added by earlier versions patching the limit in the submodule, dropped from the copy
+const PATCH_MARKER: &str = "// Patched by libvfio-user-rs";
+
+fn dma_limit(patch_dma_limit: bool) -> Result<usize> {
+    println!("cargo:rerun-if-env-changed={}", DMA_LIMIT_ENV);
+
+    match env::var(DMA_LIMIT_ENV) {
+        Ok(value) => {
+            let limit = value
+                .parse()
+                .map_err(|e| anyhow!("Invalid {}={}: {}", DMA_LIMIT_ENV, value, e))?;
+            ensure!(limit > 0, "{} must be at least 1", DMA_LIMIT_ENV);
+            Ok(limit)
+        }
+        Err(_) if patch_dma_limit => Ok(PATCHED_DMA_LIMIT),
+        Err(_) => Ok(DEFAULT_DMA_LIMIT),
+    }
+}
+
+// Mirror the submodule sources into the build directory, which is patched instead of the submodule.
+// Unchanged files are not touched, meson would rebuild everything otherwise
+fn copy_sources(source: &Path, target: &Path) -> Result<()> {
+    fs::create_dir_all(target)?;
+    for entry in
+        fs::read_dir(source).map_err(|e| anyhow!("Failed to read {}: {}", source.display(), e))?
+    {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        let source_path = entry.path();
+        let target_path = target.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_sources(&source_path, &target_path)?;
+        } else if fs::read(&target_path).ok() != Some(fs::read(&source_path)?) {
+            fs::copy(&source_path, &target_path)?;
+        }
+    }
+    Ok(())
+}
+
+// Rewrite the MAX_DMA_REGIONS define, libvfio-user has no build option for it
+fn set_dma_limit(target_path: &Path, limit: usize) -> Result<()> {
+    let define = "#define MAX_DMA_REGIONS ";
     let contents = fs::read_to_string(target_path)?;
 
-    if contents.contains(keyword) {
-        // Already patched
+    let mut found = false;
+    let mut patched = String::with_capacity(contents.len());
+    for line in contents.lines() {
+        if line == PATCH_MARKER {
+            continue;
+        }
+        if line.starts_with(define) {
+            found = true;
+            patched.push_str(&format!("{}{}\n", define, limit));
+        } else {
+            patched.push_str(line);
+            patched.push('\n');
+        }
+    }
+    ensure!(
+        found,
+        "MAX_DMA_REGIONS is not defined in {}",
+        target_path.display()
+    );
+
+    // Avoid touching the file if nothing changes, meson would rebuild otherwise
+    if patched != contents {
+        fs::write(target_path, patched)?;
+    }
+    Ok(())
+}
+
+// Make the effective limit available as libvfio_user_sys::MAX_DMA_REGIONS
+fn write_dma_limit(out_path: &Path, limit: usize) {
+    let contents = format!(
+        "/// Maximum number of dma regions a client may register, chosen at build time\n\
+         pub const MAX_DMA_REGIONS: usize = {};\n",
+        limit
+    );
+    fs::write(out_path.join("dma_limit.rs"), contents).expect("Couldn't write dma limit!");
+}
+
+// Find an installed libvfio-user, pkg-config configures cargo to link it.
+// Returns the header and include paths to generate bindings with
+fn probe_system() -> Result<(PathBuf, Vec<PathBuf>)> {
+    // No minimum version is requested: libvfio-user has announced version 0.0.1 since its first
+    // release, so only check_system_api tells whether the library is recent enough.
+    // Older releases install the pkg-config file without the lib prefix
+    let library = ["libvfio-user", "vfio-user"]
+        .iter()
+        .find_map(|name| {
+            pkg_config::Config::new()
+                .cargo_metadata(true)
+                .probe(name)
+                .ok()
+        })
+        .ok_or_else(|| anyhow!("Could not find libvfio-user with pkg-config"))?;
+
+    // Headers are installed into a vfio-user subdirectory, which may or may not be part of the
+    // include path depending on the version
+    let header_path = library
+        .include_paths
+        .iter()
+        .flat_map(|path| {
+            [
+                path.join("libvfio-user.h"),
+                path.join("vfio-user/libvfio-user.h"),
+            ]
+        })
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            anyhow!(
+                "Could not find libvfio-user.h in {:?}",
+                library.include_paths
+            )
+        })?;
+
+    check_system_api(&header_path)?;
+
+    Ok((header_path, library.include_paths))
+}
+
+// Fail early with a readable error rather than with unresolved bindings in the wrapper
+fn check_system_api(header_path: &Path) -> Result<()> {
+    let header = fs::read_to_string(header_path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", header_path.display(), e))?;
+
+    let missing: Vec<&str> = SYSTEM_REQUIRED_API
+        .iter()
+        .copied()
+        .filter(|function| {
+            !header
+                .match_indices(function)
+                .any(|(start, _)| is_declaration(&header, start, function.len()))
+        })
+        .collect();
+
+    ensure!(
+        missing.is_empty(),
+        "libvfio-user at {} is too old, it lacks {}",
+        header_path.display(),
+        missing.join(", ")
+    );
+    Ok(())
+}
+
+// Whether the name at start is a whole identifier followed by an argument list
+fn is_declaration(header: &str, start: usize, len: usize) -> bool {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let before = header[..start].chars().next_back();
+    let after = header[start + len..].trim_start().chars().next();
+    !before.is_some_and(is_ident) && after == Some('(')
+}
+
+// FNV-1a of the header the bindings were generated from, stable across Rust versions
+fn header_hash(header_path: &Path) -> Result<String> {
+    let contents = fs::read(header_path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", header_path.display(), e))?;
+
+    let hash = contents.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    Ok(format!("{:016x}", hash))
+}
+
+// First line of pre-generated bindings, followed by the header hash
+fn hash_prefix(header_path: &Path) -> String {
+    let file_name = header_path.file_name().unwrap().to_string_lossy();
+    format!("// {} fnv1a64: ", file_name)
+}
+
+// Copy the checked-in bindings after making sure they match the header
+#[cfg(not(feature = "bindgen"))]
+fn use_pregenerated_bindings(
+    header_path: &Path, pregenerated: &str, bindings_path: &Path,
+) -> Result<()> {
+    println!("cargo:rerun-if-changed={}", pregenerated);
+    println!("cargo:rerun-if-changed={}", header_path.display());
+
+    let bindings = fs::read_to_string(pregenerated).map_err(|e| {
+        anyhow!(
+            "Failed to read pre-generated bindings {}, enable feature bindgen: {}",
+            pregenerated,
+            e
+        )
+    })?;
+
+    let expected = bindings
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix(&hash_prefix(header_path)))
+        .ok_or_else(|| anyhow!("{} lacks the header hash", pregenerated))?;
+    if expected == UNVERIFIED_HASH {
+        println!(
+            "cargo:warning={} was not generated from {}, regenerate it with {}=1",
+            pregenerated,
+            header_path.display(),
+            UPDATE_BINDINGS_ENV
+        );
+        fs::write(bindings_path, bindings)?;
         return Ok(());
     }
 
-    let patch_contents = fs::read_to_string(patch_path)?;
+    let actual = header_hash(header_path)?;
+    ensure!(
+        expected == actual,
+        "{} does not match the pre-generated bindings (hash {} != {}), enable feature bindgen \
+         or regenerate them with {}=1",
+        header_path.display(),
+        actual,
+        expected,
+        UPDATE_BINDINGS_ENV
+    );
+
+    fs::write(bindings_path, bindings)?;
+    Ok(())
+}
+
+// Bindings for everything declared in the header, or only the given type if any
+#[cfg(feature = "bindgen")]
+fn generate_bindings(
+    header_path: &Path, include_paths: &[PathBuf], only_type: Option<&str>,
+    pregenerated: Option<&str>, bindings_path: &Path,
+) {
+    let header_path_str = header_path.to_str().unwrap();
+
+    // The bindgen::Builder is the main entry point
+    // to bindgen, and lets you build up options for
+    // the resulting bindings.
+    let mut builder = bindgen::Builder::default()
+        // The input header we would like to generate
+        // bindings for.
+        .header(header_path_str);
+
+    builder = match only_type {
+        // Types of the public header are already part of the main bindings
+        Some(only_type) => builder
+            .allowlist_type(only_type)
+            .blocklist_file(".*/libvfio-user.h"),
+        None => builder.allowlist_file(header_path_str),
+    };
+
+    let bindings = builder
+        // Parse all comments since some explanations are not doc comments (/* ... */ vs /** ... */)
+        .clang_arg("-fparse-all-comments")
+        // Headers included by libvfio-user.h, e.g. of an installed library
+        .clang_args(
+            include_paths
+                .iter()
+                .map(|path| format!("-I{}", path.display())),
+        )
+        // Tell cargo to invalidate the built crate whenever any of the
+        // included header files changed.
+        .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
+        // Finish the builder and generate the bindings.
+        .generate()
+        // Unwrap the Result and panic on failure.
+        .expect("Unable to generate bindings");
+
+    // Write the bindings to the $OUT_DIR/bindings.rs file.
+    bindings
+        .write_to_file(bindings_path)
+        .expect("Couldn't write bindings!");
 
-    let patch = Patch::from_str(&*patch_contents)?;
-    let contents = apply(&*contents, &patch)?;
+    // Check in the bindings for builds without libclang, prefixed by the header hash
+    let Some(pregenerated) = pregenerated else {
+        return;
+    };
+    println!("cargo:rerun-if-env-changed={}", UPDATE_BINDINGS_ENV);
+    if env::var_os(UPDATE_BINDINGS_ENV).is_some() {
+        let contents = format!(
+            "{}{}\n{}",
+            hash_prefix(header_path),
+            header_hash(header_path).unwrap(),
+            bindings
+        );
+        fs::write(pregenerated, contents).expect("Couldn't update bindings!");
+    }
+}
 
-    fs::write(target_path, contents)?;
+// Copy the checked-in bindings as they are, for builds without the headers they came from
+fn copy_pregenerated(pregenerated: &str, bindings_path: &Path) -> Result<()> {
+    println!("cargo:rerun-if-changed={}", pregenerated);
+    fs::copy(pregenerated, bindings_path)
+        .map_err(|e| anyhow!("Failed to copy {}: {}", pregenerated, e))?;
     Ok(())
 }
 
+fn provide_bindings(
+    header_path: &Path, include_paths: &[PathBuf], only_type: Option<&str>, pregenerated: &str,
+    bindings_path: &Path,
+) {
+    #[cfg(feature = "bindgen")]
+    generate_bindings(
+        header_path,
+        include_paths,
+        only_type,
+        Some(pregenerated),
+        bindings_path,
+    );
+
+    #[cfg(not(feature = "bindgen"))]
+    {
+        // Only needed to generate bindings
+        let _ = (include_paths, only_type);
+        use_pregenerated_bindings(header_path, pregenerated, bindings_path).unwrap();
+    }
+}
+
 fn main() {
+    let bindings_only = cfg!(feature = "bindings-only");
+    let system = cfg!(feature = "system");
     let build_static = cfg!(feature = "build-static");
     let build_shared = cfg!(feature = "build-shared");
     let patch_dma_limit = cfg!(feature = "patch-dma-limit");
 
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let bindings_path = out_path.join("bindings.rs");
+    let dma_sg_path = out_path.join("dma_sg.rs");
+
+    let dma_limit = dma_limit(patch_dma_limit).unwrap();
+
+    // Only declarations for an implementation provided elsewhere, nothing is built or linked
+    if bindings_only {
+        if system || build_static || build_shared {
+            println!(
+                "cargo:warning=Feature bindings-only takes precedence, libvfio-user is not linked"
+            );
+        }
+        write_dma_limit(&out_path, dma_limit);
+
+        // Neither the submodule nor libclang are needed, the bindings are used as checked in
+        if cfg!(feature = "bindgen") {
+            println!("cargo:warning=Feature bindgen has no effect with bindings-only");
+        }
+        copy_pregenerated(PREGENERATED_BINDINGS, &bindings_path).unwrap();
+        copy_pregenerated(PREGENERATED_DMA_SG, &dma_sg_path).unwrap();
+        return;
+    }
+
+    // Use an installed library, the submodule is not needed at all
+    if system {
+        if build_static || build_shared {
+            println!("cargo:warning=Feature system takes precedence, libvfio-user is not built");
+        }
+        // The limit is compiled into the library, it can only be declared via the environment
+        if patch_dma_limit {
+            println!("cargo:warning=Feature patch-dma-limit has no effect on a system library");
+        }
+        write_dma_limit(
+            &out_path,
+            env::var(DMA_LIMIT_ENV).map_or(DEFAULT_DMA_LIMIT, |_| dma_limit),
+        );
+
+        // The checked-in bindings are for the submodule, feature system enables bindgen to
+        // generate them from the installed header instead
+        let (header_path, include_paths) = probe_system().unwrap();
+        #[cfg(feature = "bindgen")]
+        generate_bindings(&header_path, &include_paths, None, None, &bindings_path);
+        #[cfg(not(feature = "bindgen"))]
+        let _ = (header_path, include_paths);
+        // The private headers are not installed, the wrapper checks the layout of upstream
+        // libvfio-user against dma_sg_size() before use
+        copy_pregenerated(PREGENERATED_DMA_SG, &dma_sg_path).unwrap();
+        return;
+    }
+
+    // Without a build feature there would be nothing to link
+    if !build_static && !build_shared {
+        panic!(
+            "No libvfio-user to link: enable build-static or build-shared to build the submodule, \
+             system to link an installed library or bindings-only for declarations only \
+             (features build-static, build-shared, system and rust-backend of libvfio-user)"
+        );
+    }
+
     // 1. Prepare paths
     let libvfio_user_path = PathBuf::from("libvfio-user");
-    let libvfio_user_path_str = libvfio_user_path.to_str().unwrap();
 
     let header_path = libvfio_user_path.join("include/libvfio-user.h");
     let header_path_str = header_path.to_str().unwrap();
 
-    let patch_path = PathBuf::from("patches/increase-dma-region-limit.patch");
-    let patch_undo_path = PathBuf::from("patches/increase-dma-region-limit_undo.patch");
-    let patch_target = libvfio_user_path.join("lib/private.h");
-
-    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
-
-    let bindings_path = out_path.join("bindings.rs");
+    // The submodule is left untouched, the limit is patched into a copy of its sources
+    let source_path = out_path.join("libvfio-user");
+    let patch_target = source_path.join("lib/private.h");
 
     let build_path = out_path.join("build");
-    let build_path_str = build_path.to_str().unwrap();
-
     let lib_path = build_path.join("lib");
     let lib_path_str = lib_path.to_str().unwrap();
 
@@ -60,12 +436,9 @@ fn main() {
         // Prefer linking statically when both static and shared libraries are built
         // Look for a `libvfio-user.a` file
         println!("cargo:rustc-link-lib=static=vfio-user");
-    } else if build_shared {
+    } else {
         // Look for a `libvfio-user.so` file
         println!("cargo:rustc-link-lib=dylib=vfio-user");
-    } else {
-        // Look for any kind of `libvfio-user` library
-        println!("cargo:rustc-link-lib=vfio-user");
     }
 
     // Tell cargo to invalidate the built crate whenever the wrapper changes
@@ -93,16 +466,18 @@ fn main() {
         println!("cargo:warning=Could not find cmocka, build may fail");
     }
 
-    // 3.1 Patch libvfio-user if requested, reverse patch if not
-    if patch_dma_limit {
-        patch(&*patch_path, &*patch_target, "MAX_DMA_REGIONS 8192").unwrap();
-    } else {
-        // Ignore errors, since patch should not have any negative side effects
-        let _ = patch(&*patch_undo_path, &*patch_target, "MAX_DMA_REGIONS 16");
-    }
+    // 3.1 Copy the sources and patch the dma region limit
+    println!("cargo:rerun-if-changed={}", libvfio_user_path.display());
+    copy_sources(&libvfio_user_path, &source_path).unwrap();
+    set_dma_limit(&patch_target, dma_limit).unwrap();
+    write_dma_limit(&out_path, dma_limit);
 
     // 3.2 Meson build
-    if build_static || build_shared {
+    #[cfg(any(feature = "build-static", feature = "build-shared"))]
+    {
+        let source_path_str = source_path.to_str().unwrap();
+        let build_path_str = build_path.to_str().unwrap();
+
         let mut meson_options = HashMap::new();
 
         if build_static && build_shared {
@@ -114,33 +489,29 @@ fn main() {
         }
 
         let meson_config = Config::new().options(meson_options);
-        meson_next::build(libvfio_user_path_str, build_path_str, meson_config);
+        meson_next::build(source_path_str, build_path_str, meson_config);
     }
 
-    // 3.3 Reverse patch, ignore errors
-    let _ = patch(&*patch_undo_path, &*patch_target, "MAX_DMA_REGIONS 16");
+    // 4. Generate or copy bindings
+    provide_bindings(
+        &header_path,
+        &[],
+        None,
+        PREGENERATED_BINDINGS,
+        &bindings_path,
+    );
 
-    // 4. Generate bindings
-    // The bindgen::Builder is the main entry point
-    // to bindgen, and lets you build up options for
-    // the resulting bindings.
-    let bindings = bindgen::Builder::default()
-        // The input header we would like to generate
-        // bindings for.
-        .header(header_path_str)
-        .allowlist_file(header_path_str)
-        // Parse all comments since some explanations are not doc comments (/* ... */ vs /** ... */)
-        .clang_arg("-fparse-all-comments")
-        // Tell cargo to invalidate the built crate whenever any of the
-        // included header files changed.
-        .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
-        // Finish the builder and generate the bindings.
-        .generate()
-        // Unwrap the Result and panic on failure.
-        .expect("Unable to generate bindings");
-
-    // Write the bindings to the $OUT_DIR/bindings.rs file.
-    bindings
-        .write_to_file(bindings_path)
-        .expect("Couldn't write bindings!");
+    // The sg entry struct is private to the library, but its layout is needed to inspect sgls
+    let dma_header_path = libvfio_user_path.join("lib/dma.h");
+    let include_paths = [
+        libvfio_user_path.join("include"),
+        libvfio_user_path.join("lib"),
+    ];
+    provide_bindings(
+        &dma_header_path,
+        &include_paths,
+        Some("dma_sg"),
+        PREGENERATED_DMA_SG,
+        &dma_sg_path,
+    );
 }
@@ -5,3 +5,11 @@
 #![allow(rustdoc::invalid_rust_codeblocks)]
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+include!(concat!(env!("OUT_DIR"), "/dma_limit.rs"));
+
+/// Private structures of libvfio-user, their layout may change between versions
+pub mod private {
+    use super::*;
+
+    include!(concat!(env!("OUT_DIR"), "/dma_sg.rs"));
+}
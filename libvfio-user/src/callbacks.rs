@@ -1,28 +1,98 @@
+use std::any::Any;
 use std::ffi::CStr;
+use std::io::Error;
 use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::process;
 use std::slice::from_raw_parts_mut;
 
 use errno::{set_errno, Errno};
 
-use libvfio_user_sys::*;
+use crate::ffi::*;
 
-use crate::{Device, DeviceRegionKind, DeviceResetReason};
+use crate::dma::DmaRegionInfo;
+use crate::socket::is_listening;
+use crate::{Device, DeviceContext, DeviceRegionKind, DeviceResetReason, PanicPolicy};
 
-// Use macro to avoid having to specify a lifetime
-macro_rules! device_from_vfu_ctx {
+// Use macros to avoid having to specify a lifetime
+macro_rules! context_from_vfu_ctx {
     ($vfu_ctx:ident) => {{
         let private = vfu_get_private($vfu_ctx);
-        &mut *(private as *mut T)
+        &*(private as *const DeviceContext)
     }};
 }
 
+macro_rules! device_from_vfu_ctx {
+    ($vfu_ctx:ident) => {{
+        let ctx = context_from_vfu_ctx!($vfu_ctx);
+        &mut *(ctx.device as *mut T)
+    }};
+}
+
+// Panics must not unwind into libvfio-user, so every call into the device goes through here
+unsafe fn catch_panic<T: Device, R>(
+    vfu_ctx: *mut vfu_ctx_t, callback: &str, f: impl FnOnce() -> R,
+) -> Option<R> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Some(result),
+        Err(payload) => {
+            handle_panic::<T>(vfu_ctx, callback, payload);
+            None
+        }
+    }
+}
+
+unsafe fn handle_panic<T: Device>(
+    vfu_ctx: *mut vfu_ctx_t, callback: &str, payload: Box<dyn Any + Send>,
+) {
+    let ctx = context_from_vfu_ctx!(vfu_ctx);
+    let policy = ctx.panic_policy.get();
+
+    let message = match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(message), _) => message,
+        (None, Some(message)) => message.as_str(),
+        (None, None) => "Box<dyn Any>",
+    };
+    let msg = format!(
+        "Device panicked in {} callback: {}, policy {:?}",
+        callback, message, policy
+    );
+
+    // The panic may have come from the log callback itself, in which case there is no one to tell
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        let device = device_from_vfu_ctx!(vfu_ctx);
+        device.log(libc::LOG_ERR, &msg);
+    }));
+
+    match policy {
+        PanicPolicy::Continue => {}
+        PanicPolicy::Disconnect => disconnect(vfu_ctx),
+        PanicPolicy::Abort => process::abort(),
+    }
+}
+
+// Shut down the connection to the client, libvfio-user then handles it like the client leaving.
+// Nothing to do if there is none, in which case the poll fd is the listening socket
+unsafe fn disconnect(vfu_ctx: *mut vfu_ctx_t) {
+    let fd = vfu_get_poll_fd(vfu_ctx);
+
+    if let Ok(false) = is_listening(fd) {
+        libc::shutdown(fd, libc::SHUT_RDWR);
+    }
+}
+
 pub(crate) unsafe extern "C" fn log_callback<T: Device>(
     vfu_ctx: *mut vfu_ctx_t, level: c_int, msg: *const c_char,
 ) {
-    let device = device_from_vfu_ctx!(vfu_ctx);
     let msg = unsafe { CStr::from_ptr(msg) };
 
-    device.log(level, msg.to_str().unwrap());
+    catch_panic::<T, _>(vfu_ctx, "log", || {
+        let device = device_from_vfu_ctx!(vfu_ctx);
+        device.log(level, &msg.to_string_lossy());
+    });
 }
 
 impl DeviceRegionKind {
@@ -47,32 +117,79 @@ impl DeviceRegionKind {
     }
 }
 
+impl DeviceContext {
+    // Returns the errno to fail the access with if the region does not permit it
+    fn check_region_access(
+        &self, region: c_int, offset: loff_t, count: usize, write: bool,
+    ) -> Result<(), c_int> {
+        let Some(limits) = self.region_limits.get(&region) else {
+            return Err(libc::EINVAL);
+        };
+
+        let permitted = if write { limits.write } else { limits.read };
+        if !permitted {
+            return Err(libc::EPERM);
+        }
+
+        let end = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| offset.checked_add(count));
+        match end {
+            Some(end) if end <= limits.size => Ok(()),
+            _ => Err(libc::EINVAL),
+        }
+    }
+}
+
 // Use R const generic to create an unique callback for each region type index
 // since we can't differentiate between regions in the callback otherwise
 pub(crate) unsafe extern "C" fn region_access_callback<T: Device, const R: u8>(
     vfu_ctx: *mut vfu_ctx_t, buf: *mut c_char, count: usize, offset: loff_t, is_write: bool,
 ) -> isize {
-    let device = device_from_vfu_ctx!(vfu_ctx);
+    let ctx = context_from_vfu_ctx!(vfu_ctx);
+
+    if let Err(error) = ctx.check_region_access(R as c_int, offset, count, is_write) {
+        let msg = format!(
+            "Rejected {} of {} bytes at {:#x} in region {}: {}",
+            if is_write { "write" } else { "read" },
+            count,
+            offset,
+            R,
+            Error::from_raw_os_error(error)
+        );
+        catch_panic::<T, _>(vfu_ctx, "log", || {
+            let device = device_from_vfu_ctx!(vfu_ctx);
+            device.log(libc::LOG_WARNING, &msg);
+        });
+
+        set_errno(Errno(error));
+        return -1;
+    }
 
     let buf = from_raw_parts_mut(buf as *mut u8, count);
     let offset = offset as usize;
 
-    // Not very pretty but compiler should at least optimize the match away
-    let result = match R {
-        0 => device.region_access_bar0(offset, buf, is_write),
-        1 => device.region_access_bar1(offset, buf, is_write),
-        2 => device.region_access_bar2(offset, buf, is_write),
-        3 => device.region_access_bar3(offset, buf, is_write),
-        4 => device.region_access_bar4(offset, buf, is_write),
-        5 => device.region_access_bar5(offset, buf, is_write),
-        6 => device.region_access_rom(offset, buf, is_write),
-        7 => device.region_access_config(offset, buf, is_write),
-        8 => device.region_access_vga(offset, buf, is_write),
-        9 => device.region_access_migration(offset, buf, is_write),
-        _ => {
-            unreachable!("Invalid region type")
+    let result = catch_panic::<T, _>(vfu_ctx, "region access", || {
+        let device = device_from_vfu_ctx!(vfu_ctx);
+
+        // Not very pretty but compiler should at least optimize the match away
+        match R {
+            0 => device.region_access_bar0(offset, buf, is_write),
+            1 => device.region_access_bar1(offset, buf, is_write),
+            2 => device.region_access_bar2(offset, buf, is_write),
+            3 => device.region_access_bar3(offset, buf, is_write),
+            4 => device.region_access_bar4(offset, buf, is_write),
+            5 => device.region_access_bar5(offset, buf, is_write),
+            6 => device.region_access_rom(offset, buf, is_write),
+            7 => device.region_access_config(offset, buf, is_write),
+            8 => device.region_access_vga(offset, buf, is_write),
+            9 => device.region_access_migration(offset, buf, is_write),
+            _ => {
+                unreachable!("Invalid region type")
+            }
         }
-    };
+    })
+    .unwrap_or(Err(libc::EIO));
 
     match result {
         Ok(bytes_processed) => bytes_processed as isize,
@@ -86,39 +203,82 @@ pub(crate) unsafe extern "C" fn region_access_callback<T: Device, const R: u8>(
 pub(crate) unsafe extern "C" fn reset_callback<T: Device>(
     vfu_ctx: *mut vfu_ctx_t, reset_type: vfu_reset_type_t,
 ) -> c_int {
-    let device = device_from_vfu_ctx!(vfu_ctx);
-
     let reason = match reset_type {
         x if x == vfu_reset_type_VFU_RESET_DEVICE => DeviceResetReason::ClientRequest,
         x if x == vfu_reset_type_VFU_RESET_LOST_CONN => DeviceResetReason::LostConnection,
         x if x == vfu_reset_type_VFU_RESET_PCI_FLR => DeviceResetReason::PciReset,
         _ => {
-            unreachable!("Invalid reset type")
+            // Newer libvfio-user versions may add reset types
+            catch_panic::<T, _>(vfu_ctx, "log", || {
+                let device = device_from_vfu_ctx!(vfu_ctx);
+                device.log(libc::LOG_ERR, &format!("Unknown reset type {}", reset_type));
+            });
+            set_errno(Errno(libc::EINVAL));
+            return -1;
         }
     };
 
-    device.reset(reason).err().unwrap_or(0)
+    let result = catch_panic::<T, _>(vfu_ctx, "reset", || {
+        let device = device_from_vfu_ctx!(vfu_ctx);
+        device.reset(reason)
+    });
+
+    match result {
+        Some(result) => result.err().unwrap_or(0),
+        None => {
+            set_errno(Errno(libc::EIO));
+            -1
+        }
+    }
 }
 
 pub(crate) unsafe extern "C" fn dma_register_callback<T: Device>(
     vfu_ctx: *mut vfu_ctx_t, info: *mut vfu_dma_info_t,
 ) {
-    let device = device_from_vfu_ctx!(vfu_ctx);
+    let ctx = context_from_vfu_ctx!(vfu_ctx);
+
+    let info = DmaRegionInfo::from_vfu_dma_info(&*info);
 
-    let info = &mut *info;
-    let base_address = info.iova.iov_base as usize;
-    let length = info.iova.iov_len;
+    // Track before notifying the device so it can already query the region
+    ctx.dma_regions.borrow_mut().insert(info.iova, info.clone());
+    let limit_reached = ctx.dma_regions.borrow().len() == MAX_DMA_REGIONS;
 
-    device.dma_range_added(base_address, length);
+    catch_panic::<T, _>(vfu_ctx, "dma register", || {
+        let device = device_from_vfu_ctx!(vfu_ctx);
+
+        // libvfio-user rejects further registrations without notifying us
+        if limit_reached {
+            device.log(
+                libc::LOG_WARNING,
+                &format!(
+                    "Dma region limit of {} reached, raise it with LIBVFIO_USER_MAX_DMA_REGIONS",
+                    MAX_DMA_REGIONS
+                ),
+            );
+        }
+
+        device.dma_range_added(&info);
+    });
 }
 
 pub(crate) unsafe extern "C" fn dma_unregister_callback<T: Device>(
     vfu_ctx: *mut vfu_ctx_t, info: *mut vfu_dma_info_t,
 ) {
-    let device = device_from_vfu_ctx!(vfu_ctx);
+    let ctx = context_from_vfu_ctx!(vfu_ctx);
 
-    let info = &mut *info;
-    let base_address = info.iova.iov_base as usize;
+    let info = DmaRegionInfo::from_vfu_dma_info(&*info);
+
+    // The region goes away regardless of whether the device handled it
+    catch_panic::<T, _>(vfu_ctx, "dma unregister", || {
+        let device = device_from_vfu_ctx!(vfu_ctx);
+        device.dma_range_removed(&info);
+    });
+
+    // libvfio-user unmaps the region once we return
+    if let Some(cache) = &ctx.dma_map_cache {
+        cache.borrow_mut().invalidate(&info);
+    }
+    ctx.revoke_dma(Some(&info));
 
-    device.dma_range_removed(base_address);
+    ctx.dma_regions.borrow_mut().remove(&info.iova);
 }
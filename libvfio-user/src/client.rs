@@ -0,0 +1,728 @@
+//! Client side of the vfio-user protocol, e.g. for lightweight VMMs, test harnesses and
+//! debugging tools talking to devices built with this crate.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Error;
+use std::mem::size_of;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, ensure, Result};
+
+use crate::memory::SharedMemory;
+use crate::protocol::{self, *};
+
+// Servers ask for a larger argsz once to fit the capabilities, more retries mean it misbehaves
+const REGION_INFO_RETRIES: usize = 3;
+
+/// Information about the device as reported by the server
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub pci: bool,
+    pub reset: bool,
+    pub num_regions: u32,
+    pub num_irqs: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct RegionInfo {
+    pub index: u32,
+    pub size: u64,
+    pub read: bool,
+    pub write: bool,
+    /// File the region can be mapped from, starting at file_offset, if the server shares it
+    pub file: Option<Arc<File>>,
+    pub file_offset: u64,
+    /// Mappable parts of the region as (offset, size), the whole region if empty
+    pub mmap_areas: Vec<(u64, u64)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct IrqInfo {
+    pub index: u32,
+    pub count: u32,
+    pub eventfd: bool,
+}
+
+// Guest memory the server may access
+struct DmaRegion {
+    size: u64,
+    memory: SharedMemory,
+    offset: usize,
+    read: bool,
+    write: bool,
+}
+
+/// Connection to a vfio-user server.
+///
+/// Guest memory handed to the server is [SharedMemory], dma the server performs via messages
+/// instead of its mapping is served from it while waiting for replies.
+pub struct Client {
+    stream: UnixStream,
+    next_msg_id: u16,
+    version: (u16, u16),
+    max_data_xfer_size: usize,
+    max_msg_fds: usize,
+    max_dma_maps: Option<usize>,
+    // Registered guest memory by iova
+    dma_regions: BTreeMap<u64, DmaRegion>,
+}
+
+impl Client {
+    /// Connect to the server listening on the socket and negotiate the protocol version
+    pub fn connect(socket_path: impl AsRef<Path>) -> Result<Client> {
+        let socket_path = socket_path.as_ref();
+        let stream = UnixStream::connect(socket_path)
+            .map_err(|e| anyhow!("Failed to connect to {}: {}", socket_path.display(), e))?;
+
+        Client::from_stream(stream)
+    }
+
+    /// Negotiate the protocol version on an already connected socket, e.g. one end of a
+    /// socketpair or a connection passed by another process
+    pub fn from_stream(stream: UnixStream) -> Result<Client> {
+        let mut client = Client {
+            stream,
+            next_msg_id: 0,
+            version: (MAJOR_VERSION, MINOR_VERSION),
+            max_data_xfer_size: MAX_DATA_XFER_SIZE,
+            max_msg_fds: 1,
+            max_dma_maps: None,
+            dma_regions: BTreeMap::new(),
+        };
+        client.negotiate()?;
+        Ok(client)
+    }
+
+    fn negotiate(&mut self) -> Result<()> {
+        let capabilities = serde_json::json!({
+            "capabilities": {
+                "max_msg_fds": MAX_MSG_FDS,
+                "max_data_xfer_size": MAX_DATA_XFER_SIZE,
+            }
+        });
+        let mut json = capabilities.to_string().into_bytes();
+        json.push(0);
+
+        let version = Version {
+            major: MAJOR_VERSION,
+            minor: MINOR_VERSION,
+        };
+        let payload = [bytemuck::bytes_of(&version), &json].concat();
+        let reply = self
+            .request(CMD_VERSION, &payload, &[])
+            .map_err(|e| anyhow!("Failed to negotiate version: {}", e))?;
+
+        let version: Version = reply.read_struct()?;
+        ensure!(
+            version.major == MAJOR_VERSION,
+            "Unsupported server version {}.{}",
+            version.major,
+            version.minor
+        );
+        self.version = (version.major, version.minor);
+
+        // Defaults apply to everything the server leaves out
+        let json = &reply.payload[size_of::<Version>()..];
+        let json = json.split(|byte| *byte == 0).next().unwrap_or_default();
+        if !json.is_empty() {
+            let value: serde_json::Value = serde_json::from_slice(json)
+                .map_err(|e| anyhow!("Invalid server capabilities: {}", e))?;
+            let capabilities = &value["capabilities"];
+
+            if let Some(size) = capabilities["max_data_xfer_size"].as_u64() {
+                // Accesses are split into chunks of this size, 0 would never make progress
+                self.max_data_xfer_size = (size as usize).clamp(1, MAX_DATA_XFER_SIZE);
+            }
+            if let Some(fds) = capabilities["max_msg_fds"].as_u64() {
+                // Eventfds and dma memory are passed as file descriptors, a server accepting
+                // none could not be set up
+                ensure!(
+                    fds > 0,
+                    "Server accepts no file descriptors (max_msg_fds=0)"
+                );
+                self.max_msg_fds = fds as usize;
+            }
+            self.max_dma_maps = capabilities["max_dma_maps"]
+                .as_u64()
+                .map(|maps| maps as usize);
+        }
+
+        Ok(())
+    }
+
+    /// Negotiated protocol version as (major, minor)
+    pub fn version(&self) -> (u16, u16) {
+        self.version
+    }
+
+    /// Largest region access or dma transfer per message, larger ones are split
+    pub fn max_data_xfer_size(&self) -> usize {
+        self.max_data_xfer_size
+    }
+
+    /// Maximum number of dma regions the server accepts, if it announced one
+    pub fn max_dma_maps(&self) -> Option<usize> {
+        self.max_dma_maps
+    }
+
+    pub fn device_info(&mut self) -> Result<DeviceInfo> {
+        let request = protocol::DeviceInfo {
+            argsz: size_of::<protocol::DeviceInfo>() as u32,
+            ..Default::default()
+        };
+        let reply = self
+            .request(CMD_DEVICE_GET_INFO, bytemuck::bytes_of(&request), &[])
+            .map_err(|e| anyhow!("Failed to get device info: {}", e))?;
+
+        let info: protocol::DeviceInfo = reply.read_struct()?;
+        Ok(DeviceInfo {
+            pci: info.flags & DEVICE_FLAG_PCI != 0,
+            reset: info.flags & DEVICE_FLAG_RESET != 0,
+            num_regions: info.num_regions,
+            num_irqs: info.num_irqs,
+        })
+    }
+
+    pub fn region_info(&mut self, index: u32) -> Result<RegionInfo> {
+        let mut argsz = size_of::<protocol::RegionInfo>() as u32;
+
+        // Retry with the size the server asks for to receive the capabilities
+        let mut retries = 0;
+        let (info, reply) = loop {
+            let request = protocol::RegionInfo {
+                argsz,
+                index,
+                ..Default::default()
+            };
+            let reply = self
+                .request(
+                    CMD_DEVICE_GET_REGION_INFO,
+                    bytemuck::bytes_of(&request),
+                    &[],
+                )
+                .map_err(|e| anyhow!("Failed to get info of region {}: {}", index, e))?;
+
+            let info: protocol::RegionInfo = reply.read_struct()?;
+            if info.argsz <= argsz {
+                break (info, reply);
+            }
+            ensure!(
+                retries < REGION_INFO_RETRIES,
+                "Server keeps asking for a larger argsz for region {}, last {}",
+                index,
+                info.argsz
+            );
+            retries += 1;
+            argsz = info.argsz;
+        };
+
+        let mut mmap_areas = Vec::new();
+        if info.flags & REGION_FLAG_CAPS != 0 {
+            let caps = reply
+                .payload
+                .get(info.cap_offset as usize..)
+                .ok_or_else(|| anyhow!("Region {} capabilities out of bounds", index))?;
+            let cap: SparseMmapCap = read_struct(caps)?;
+
+            if cap.id == REGION_CAP_SPARSE_MMAP {
+                let areas = &caps[size_of::<SparseMmapCap>()..];
+                for i in 0..cap.nr_areas as usize {
+                    let area = areas.get(i * size_of::<SparseMmapArea>()..);
+                    let area: SparseMmapArea = read_struct(area.unwrap_or_default())?;
+                    mmap_areas.push((area.offset, area.size));
+                }
+            }
+        }
+
+        let file = match (
+            info.flags & REGION_FLAG_MMAP != 0,
+            reply.fds.into_iter().next(),
+        ) {
+            (true, Some(fd)) => Some(Arc::new(File::from(fd))),
+            _ => None,
+        };
+
+        Ok(RegionInfo {
+            index,
+            size: info.size,
+            read: info.flags & REGION_FLAG_READ != 0,
+            write: info.flags & REGION_FLAG_WRITE != 0,
+            file,
+            file_offset: info.offset,
+            mmap_areas,
+        })
+    }
+
+    /// Information about all regions of the device
+    pub fn regions(&mut self) -> Result<Vec<RegionInfo>> {
+        let num_regions = self.device_info()?.num_regions;
+        (0..num_regions)
+            .map(|index| self.region_info(index))
+            .collect()
+    }
+
+    pub fn irq_info(&mut self, index: u32) -> Result<IrqInfo> {
+        let request = protocol::IrqInfo {
+            argsz: size_of::<protocol::IrqInfo>() as u32,
+            index,
+            ..Default::default()
+        };
+        let reply = self
+            .request(CMD_DEVICE_GET_IRQ_INFO, bytemuck::bytes_of(&request), &[])
+            .map_err(|e| anyhow!("Failed to get info of irq {}: {}", index, e))?;
+
+        let info: protocol::IrqInfo = reply.read_struct()?;
+        Ok(IrqInfo {
+            index,
+            count: info.count,
+            eventfd: info.flags & IRQ_INFO_FLAG_EVENTFD != 0,
+        })
+    }
+
+    pub fn region_read(&mut self, index: u32, offset: u64, data: &mut [u8]) -> Result<()> {
+        let mut offset = offset;
+        for chunk in data.chunks_mut(self.max_data_xfer_size) {
+            let access = RegionAccess {
+                offset,
+                region: index,
+                count: chunk.len() as u32,
+            };
+            let reply = self
+                .request(CMD_REGION_READ, bytemuck::bytes_of(&access), &[])
+                .map_err(|e| anyhow!("Failed to read region {}: {}", index, e))?;
+
+            let read = reply
+                .payload
+                .get(size_of::<RegionAccess>()..)
+                .filter(|read| read.len() == chunk.len())
+                .ok_or_else(|| anyhow!("Failed to read region {}: short reply", index))?;
+            chunk.copy_from_slice(read);
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    pub fn region_write(&mut self, index: u32, offset: u64, data: &[u8]) -> Result<()> {
+        let mut offset = offset;
+        for chunk in data.chunks(self.max_data_xfer_size) {
+            let access = RegionAccess {
+                offset,
+                region: index,
+                count: chunk.len() as u32,
+            };
+            let payload = [bytemuck::bytes_of(&access), chunk].concat();
+            self.request(CMD_REGION_WRITE, &payload, &[])
+                .map_err(|e| anyhow!("Failed to write region {}: {}", index, e))?;
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Make `size` bytes of the memory starting at `offset` available to the device at `iova`
+    pub fn dma_map(
+        &mut self, iova: u64, memory: &SharedMemory, offset: usize, size: usize, read: bool,
+        write: bool,
+    ) -> Result<()> {
+        ensure!(
+            offset
+                .checked_add(size)
+                .is_some_and(|end| end <= memory.size()),
+            "Dma region exceeds the shared memory"
+        );
+
+        let mut flags = 0;
+        if read {
+            flags |= DMA_FLAG_READ;
+        }
+        if write {
+            flags |= DMA_FLAG_WRITE;
+        }
+        let request = DmaMap {
+            argsz: size_of::<DmaMap>() as u32,
+            flags,
+            offset: offset as u64,
+            addr: iova,
+            size: size as u64,
+        };
+        self.request(
+            CMD_DMA_MAP,
+            bytemuck::bytes_of(&request),
+            &[memory.as_raw_fd()],
+        )
+        .map_err(|e| anyhow!("Failed to map dma region {:#x}: {}", iova, e))?;
+
+        self.dma_regions.insert(
+            iova,
+            DmaRegion {
+                size: size as u64,
+                memory: memory.clone(),
+                offset,
+                read,
+                write,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn dma_unmap(&mut self, iova: u64, size: u64) -> Result<()> {
+        let request = DmaUnmap {
+            argsz: size_of::<DmaUnmap>() as u32,
+            flags: 0,
+            addr: iova,
+            size,
+        };
+        self.request(CMD_DMA_UNMAP, bytemuck::bytes_of(&request), &[])
+            .map_err(|e| anyhow!("Failed to unmap dma region {:#x}: {}", iova, e))?;
+
+        self.dma_regions.remove(&iova);
+        Ok(())
+    }
+
+    pub fn dma_unmap_all(&mut self) -> Result<()> {
+        let request = DmaUnmap {
+            argsz: size_of::<DmaUnmap>() as u32,
+            flags: DMA_UNMAP_FLAG_ALL,
+            addr: 0,
+            size: 0,
+        };
+        self.request(CMD_DMA_UNMAP, bytemuck::bytes_of(&request), &[])
+            .map_err(|e| anyhow!("Failed to unmap all dma regions: {}", e))?;
+
+        self.dma_regions.clear();
+        Ok(())
+    }
+
+    /// Signal the interrupts of type `index` starting at subindex `start` via the eventfds
+    pub fn set_irq_eventfds(
+        &mut self, index: u32, start: u32, eventfds: &[BorrowedFd],
+    ) -> Result<()> {
+        ensure!(
+            eventfds.len() <= self.max_msg_fds,
+            "Server accepts at most {} file descriptors per message",
+            self.max_msg_fds
+        );
+
+        let request = IrqSet {
+            argsz: size_of::<IrqSet>() as u32,
+            flags: IRQ_SET_DATA_EVENTFD | IRQ_SET_ACTION_TRIGGER,
+            index,
+            start,
+            count: eventfds.len() as u32,
+        };
+        let fds: Vec<RawFd> = eventfds.iter().map(|fd| fd.as_raw_fd()).collect();
+        self.request(CMD_DEVICE_SET_IRQS, bytemuck::bytes_of(&request), &fds)
+            .map_err(|e| anyhow!("Failed to set eventfds of irq {}: {}", index, e))?;
+        Ok(())
+    }
+
+    /// Create an eventfd for each interrupt of type `index` and hand them to the server
+    pub fn enable_irqs(&mut self, index: u32) -> Result<Vec<OwnedFd>> {
+        let count = self.irq_info(index)?.count;
+
+        let eventfds = (0..count)
+            .map(|_| {
+                let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+                match fd {
+                    ..=-1 => Err(anyhow!(
+                        "Failed to create eventfd: {}",
+                        Error::last_os_error()
+                    )),
+                    fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let borrowed: Vec<BorrowedFd> = eventfds.iter().map(|fd| fd.as_fd()).collect();
+        for (i, chunk) in borrowed.chunks(self.max_msg_fds).enumerate() {
+            self.set_irq_eventfds(index, (i * self.max_msg_fds) as u32, chunk)?;
+        }
+        Ok(eventfds)
+    }
+
+    /// Stop signalling the interrupts of type `index`
+    pub fn disable_irqs(&mut self, index: u32) -> Result<()> {
+        let request = IrqSet {
+            argsz: size_of::<IrqSet>() as u32,
+            flags: IRQ_SET_DATA_NONE | IRQ_SET_ACTION_TRIGGER,
+            index,
+            start: 0,
+            count: 0,
+        };
+        self.request(CMD_DEVICE_SET_IRQS, bytemuck::bytes_of(&request), &[])
+            .map_err(|e| anyhow!("Failed to disable irq {}: {}", index, e))?;
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<()> {
+        self.request(CMD_DEVICE_RESET, &[], &[])
+            .map_err(|e| anyhow!("Failed to reset device: {}", e))?;
+        Ok(())
+    }
+
+    // Send a command and wait for its reply, serving dma requests of the server meanwhile
+    fn request(&mut self, command: u16, payload: &[u8], fds: &[RawFd]) -> Result<Message> {
+        let msg_id = self.next_msg_id;
+        self.next_msg_id = msg_id.wrapping_add(1);
+
+        let header = Header {
+            msg_id,
+            command,
+            msg_size: (size_of::<Header>() + payload.len()) as u32,
+            flags: FLAG_TYPE_COMMAND,
+            error_no: 0,
+        };
+        send_message(&self.stream, &header, payload, fds)?;
+
+        loop {
+            let msg =
+                recv_message(&self.stream, false)?.ok_or_else(|| anyhow!("Connection closed"))?;
+
+            if !msg.header.is_reply() {
+                self.serve(&msg)?;
+                continue;
+            }
+
+            ensure!(
+                msg.header.msg_id == msg_id,
+                "Unexpected reply to message {}",
+                msg.header.msg_id
+            );
+            if msg.header.flags & FLAG_ERROR != 0 {
+                return Err(Error::from_raw_os_error(msg.header.error_no as i32).into());
+            }
+            return Ok(msg);
+        }
+    }
+
+    // Answer a command of the server, only dma is expected
+    fn serve(&self, msg: &Message) -> Result<()> {
+        let header = msg.header;
+        let result = match header.command {
+            CMD_DMA_READ | CMD_DMA_WRITE => self.serve_dma(msg),
+            _ => Err(libc::ENOTSUP),
+        };
+
+        if header.flags & FLAG_NO_REPLY != 0 {
+            return Ok(());
+        }
+
+        match result {
+            Ok(payload) => send_message(&self.stream, &header.reply(payload.len()), &payload, &[])?,
+            Err(errno) => send_message(&self.stream, &header.error_reply(errno), &[], &[])?,
+        }
+        Ok(())
+    }
+
+    fn serve_dma(&self, msg: &Message) -> Result<Vec<u8>, i32> {
+        let access: DmaAccess = msg.read_struct().map_err(|_| libc::EINVAL)?;
+        let write = msg.header.command == CMD_DMA_WRITE;
+
+        // Transfers must stay within a single region
+        let (iova, region) = self
+            .dma_regions
+            .range(..=access.addr)
+            .next_back()
+            .ok_or(libc::EFAULT)?;
+        let offset = access.addr - iova;
+        match offset.checked_add(access.count) {
+            Some(end) if end <= region.size => {}
+            _ => return Err(libc::EFAULT),
+        }
+        if (write && !region.write) || (!write && !region.read) {
+            return Err(libc::EACCES);
+        }
+
+        let memory_offset = region.offset + offset as usize;
+        let access_bytes = bytemuck::bytes_of(&access);
+        if write {
+            let data = &msg.payload[size_of::<DmaAccess>()..];
+            if data.len() as u64 != access.count {
+                return Err(libc::EINVAL);
+            }
+            region
+                .memory
+                .write_volatile(data, memory_offset)
+                .map_err(|_| libc::EFAULT)?;
+            Ok(access_bytes.to_vec())
+        } else {
+            let data = region
+                .memory
+                .read_volatile(access.count as usize, memory_offset)
+                .map_err(|_| libc::EFAULT)?;
+            Ok([access_bytes, &data].concat())
+        }
+    }
+}
+
+impl AsFd for Client {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.stream.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use super::*;
+
+    // Server announcing the capabilities, then answering every command with the payload the
+    // handler returns. Returns the commands received until the client disconnected
+    fn fake_server(
+        capabilities: &'static str, mut handler: impl FnMut(&Message) -> Vec<u8> + Send + 'static,
+    ) -> (UnixStream, JoinHandle<Vec<Message>>) {
+        let (client_end, server_end) = UnixStream::pair().unwrap();
+        client_end
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut received = Vec::new();
+            while let Ok(Some(msg)) = recv_message(&server_end, false) {
+                let payload = match msg.header.command {
+                    CMD_VERSION => {
+                        let version = Version {
+                            major: MAJOR_VERSION,
+                            minor: MINOR_VERSION,
+                        };
+                        [bytemuck::bytes_of(&version), capabilities.as_bytes(), &[0]].concat()
+                    }
+                    _ => handler(&msg),
+                };
+                let reply = msg.header.reply(payload.len());
+                send_message(&server_end, &reply, &payload, &[]).unwrap();
+                received.push(msg);
+            }
+            received
+        });
+
+        (client_end, handle)
+    }
+
+    #[test]
+    fn zero_max_data_xfer_size() {
+        let capabilities = r#"{"capabilities": {"max_data_xfer_size": 0}}"#;
+        let (stream, handle) = fake_server(capabilities, |msg| {
+            let access: RegionAccess = msg.read_struct().unwrap();
+            match msg.header.command {
+                CMD_REGION_READ => {
+                    let data = vec![access.offset as u8; access.count as usize];
+                    [bytemuck::bytes_of(&access), &data].concat()
+                }
+                _ => bytemuck::bytes_of(&access).to_vec(),
+            }
+        });
+
+        let mut client = Client::from_stream(stream).unwrap();
+        assert_eq!(client.max_data_xfer_size(), 1);
+
+        let mut data = [0; 3];
+        client.region_read(0, 0x10, &mut data).unwrap();
+        assert_eq!(data, [0x10, 0x11, 0x12]);
+        client.region_write(0, 0x20, &[1, 2]).unwrap();
+
+        drop(client);
+        let accesses: Vec<(u16, u64, u32)> = handle.join().unwrap()[1..]
+            .iter()
+            .map(|msg| {
+                let access: RegionAccess = msg.read_struct().unwrap();
+                (msg.header.command, access.offset, access.count)
+            })
+            .collect();
+        assert_eq!(
+            accesses,
+            [
+                (CMD_REGION_READ, 0x10, 1),
+                (CMD_REGION_READ, 0x11, 1),
+                (CMD_REGION_READ, 0x12, 1),
+                (CMD_REGION_WRITE, 0x20, 1),
+                (CMD_REGION_WRITE, 0x21, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn region_info_growing_argsz() {
+        // Asks for more than the client sent every time
+        let (stream, handle) = fake_server("", |msg| {
+            let request: protocol::RegionInfo = msg.read_struct().unwrap();
+            let reply = protocol::RegionInfo {
+                argsz: request.argsz + 8,
+                flags: REGION_FLAG_READ,
+                index: request.index,
+                size: 0x1000,
+                ..Default::default()
+            };
+            bytemuck::bytes_of(&reply).to_vec()
+        });
+
+        let mut client = Client::from_stream(stream).unwrap();
+        assert!(client.region_info(0).is_err());
+
+        drop(client);
+        let requests = handle.join().unwrap().len() - 1;
+        assert_eq!(requests, REGION_INFO_RETRIES + 1);
+    }
+
+    #[test]
+    fn zero_max_msg_fds() {
+        let capabilities = r#"{"capabilities": {"max_msg_fds": 0}}"#;
+        let (stream, handle) = fake_server(capabilities, |_| Vec::new());
+
+        let Err(err) = Client::from_stream(stream) else {
+            panic!("max_msg_fds=0 was accepted");
+        };
+        assert!(err.to_string().contains("max_msg_fds=0"), "{}", err);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn enable_irqs_in_chunks() {
+        let capabilities = r#"{"capabilities": {"max_msg_fds": 2}}"#;
+        let (stream, handle) = fake_server(capabilities, |msg| match msg.header.command {
+            CMD_DEVICE_GET_IRQ_INFO => {
+                let request: protocol::IrqInfo = msg.read_struct().unwrap();
+                let reply = protocol::IrqInfo {
+                    count: 5,
+                    ..request
+                };
+                bytemuck::bytes_of(&reply).to_vec()
+            }
+            _ => Vec::new(),
+        });
+
+        let mut client = Client::from_stream(stream).unwrap();
+        assert_eq!(client.enable_irqs(2).unwrap().len(), 5);
+
+        drop(client);
+        let sets: Vec<(u32, u32, usize)> = handle.join().unwrap()[2..]
+            .iter()
+            .map(|msg| {
+                let request: IrqSet = msg.read_struct().unwrap();
+                (request.start, request.count, msg.fds.len())
+            })
+            .collect();
+        assert_eq!(sets, [(0, 2, 2), (2, 2, 2), (4, 1, 1)]);
+    }
+
+    #[test]
+    fn malformed_capabilities() {
+        // Anything but an object leaves the defaults in place, invalid json is rejected
+        for (capabilities, valid) in [("[]", true), ("1", true), ("not json", false)] {
+            let (stream, handle) = fake_server(capabilities, |_| Vec::new());
+            let client = Client::from_stream(stream);
+            assert_eq!(client.is_ok(), valid, "Capabilities {}", capabilities);
+            if let Ok(client) = &client {
+                assert_eq!(client.max_data_xfer_size(), MAX_DATA_XFER_SIZE);
+            }
+
+            drop(client);
+            handle.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserializer;
+
+use crate::{DeviceConfiguration, DeviceConfigurator, DeviceConfiguratorError};
+
+impl DeviceConfigurator {
+    /// Load a configurator from a TOML, JSON or YAML file, the format is chosen by file extension.
+    ///
+    /// Region backing files and shared memory can not be described in a file,
+    /// they have to be added to the returned configurator in code.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration {}", path.display()))?;
+
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+
+        let configurator = match extension {
+            "toml" => deserialize(toml::Deserializer::new(&contents)),
+            "json" => deserialize(&mut serde_json::Deserializer::from_str(&contents)),
+            "yaml" | "yml" => deserialize(serde_yaml::Deserializer::from_str(&contents)),
+            _ => Err(anyhow!(
+                "Unknown configuration format {:?}, expected toml, json or yaml",
+                extension
+            )),
+        }
+        .with_context(|| format!("Invalid configuration {}", path.display()))?;
+
+        // Run the checks done when building here as well, so the error is tied to the file
+        configurator
+            .validate()
+            .map_err(|e| anyhow!("Invalid configuration {}: {}", path.display(), e))?;
+
+        Ok(configurator)
+    }
+}
+
+fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DeviceConfigurator> {
+    serde_path_to_error::deserialize(deserializer)
+        .map_err(|e| anyhow!("{}: {}", e.path(), e.inner()))
+}
+
+impl TryFrom<DeviceConfigurator> for DeviceConfiguration {
+    type Error = DeviceConfiguratorError;
+
+    fn try_from(configurator: DeviceConfigurator) -> Result<Self, Self::Error> {
+        configurator.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{DeviceRegionKind, InterruptRequestKind, PanicPolicy, PciType};
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn load(name: &str) -> DeviceConfiguration {
+        DeviceConfigurator::from_file(fixture(name))
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    fn error(path: &Path) -> String {
+        let Err(err) = DeviceConfigurator::from_file(path) else {
+            panic!("{} was accepted", path.display());
+        };
+        format!("{:#}", err)
+    }
+
+    #[test]
+    fn formats_parse_alike() {
+        for name in ["device.toml", "device.json", "device.yaml"] {
+            let config = load(name);
+
+            assert_eq!(
+                config.socket_path,
+                Path::new("/run/vfio-user/device.sock"),
+                "{}",
+                name
+            );
+            assert_eq!(config.socket_mode, Some(0o600), "{}", name);
+            assert!(matches!(config.pci_type, PciType::PciExpress), "{}", name);
+            assert!(config.setup_dma, "{}", name);
+            assert!(!config.overwrite_socket, "{}", name);
+            assert_eq!(config.panic_policy, PanicPolicy::Disconnect, "{}", name);
+
+            assert_eq!(config.pci_config.vendor_id, 0x1234, "{}", name);
+            assert_eq!(config.pci_config.device_id, 0x11e8, "{}", name);
+            assert_eq!(config.pci_config.revision_id, 0x10, "{}", name);
+
+            assert_eq!(config.device_regions.len(), 2, "{}", name);
+            let bar0 = &config.device_regions[0];
+            assert!(
+                matches!(bar0.region_type, DeviceRegionKind::Bar0),
+                "{}",
+                name
+            );
+            assert_eq!(bar0.size, 0x100000, "{}", name);
+            assert_eq!(bar0.offset, 0, "{}", name);
+            assert!(bar0.read && bar0.write && bar0.memory, "{}", name);
+            assert!(bar0.file.is_none() && bar0.shared_memory.is_none());
+            assert!(
+                matches!(
+                    config.device_regions[1].region_type,
+                    DeviceRegionKind::Config {
+                        always_callback: true
+                    }
+                ),
+                "{}",
+                name
+            );
+
+            let irqs = &config.interrupt_request_counts;
+            assert_eq!(irqs.len(), 2, "{}", name);
+            assert_eq!(irqs[&InterruptRequestKind::IntX], 1, "{}", name);
+            assert_eq!(irqs[&InterruptRequestKind::Msi], 2, "{}", name);
+        }
+    }
+
+    #[test]
+    fn errors_name_the_path() {
+        let err = error(&fixture("invalid-type.yaml"));
+        assert!(err.contains("invalid-type.yaml"), "{}", err);
+        assert!(err.contains("device_regions[0].size"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let err = error(&fixture("unknown-field.json"));
+        assert!(err.contains("socket_permissions"), "{}", err);
+    }
+
+    #[test]
+    fn configurations_are_validated() {
+        let err = error(&fixture("duplicate-region.toml"));
+        assert!(err.contains("duplicate-region.toml"), "{}", err);
+        assert!(
+            err.contains("device_regions[1].region_type: Duplicate device region"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn unreadable_files_are_rejected() {
+        let err = error(&fixture("missing.toml"));
+        assert!(err.contains("Failed to read configuration"), "{}", err);
+
+        let path =
+            std::env::temp_dir().join(format!("vfio-user-config-{}.ini", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let err = error(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(
+            err.contains("Unknown configuration format \"ini\""),
+            "{}",
+            err
+        );
+    }
+}
@@ -1,102 +1,260 @@
+use std::cell::Cell;
 use std::fmt::{Debug, Formatter};
 use std::io::Error;
 use std::mem::size_of;
-use std::os::raw::c_void;
+use std::ops::Range;
+use std::os::raw::{c_int, c_void};
 use std::ptr::null_mut;
+use std::rc::Rc;
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 use anyhow::{anyhow, ensure, Result};
 
-use libvfio_user_sys::*;
+use crate::ffi::*;
 
 use crate::DeviceContext;
 
+pub(crate) use self::cache::DmaMapCache;
+pub use self::cache::DmaMapCacheStats;
+use self::object::{volatile_copy_from, volatile_copy_to};
+pub use self::object::{Be16, Be32, Be64, Le16, Le32, Le64};
+
+mod cache;
+mod io;
+mod object;
+pub mod ring;
+
+/// Guest memory region the client registered for dma
+#[derive(Clone, Debug)]
+pub struct DmaRegionInfo {
+    /// Guest address the region starts at
+    pub iova: usize,
+    pub length: usize,
+    /// Address iova is mapped at in this process, None if the region is not mappable
+    pub vaddr: Option<usize>,
+    /// Whole host mapping containing the region, it may be larger due to alignment
+    pub mapping: Option<Range<usize>>,
+    pub page_size: usize,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl DmaRegionInfo {
+    pub(crate) fn from_vfu_dma_info(info: &vfu_dma_info_t) -> DmaRegionInfo {
+        let mapping_base = info.mapping.iov_base as usize;
+
+        DmaRegionInfo {
+            iova: info.iova.iov_base as usize,
+            length: info.iova.iov_len,
+            vaddr: (!info.vaddr.is_null()).then_some(info.vaddr as usize),
+            mapping: (!info.mapping.iov_base.is_null())
+                .then_some(mapping_base..mapping_base + info.mapping.iov_len),
+            page_size: info.page_size,
+            readable: info.prot & libc::PROT_READ as u32 != 0,
+            writable: info.prot & libc::PROT_WRITE as u32 != 0,
+        }
+    }
+
+    /// Whether the client shared a file descriptor for the region which is mapped in this process,
+    /// otherwise accesses have to go through the client via messages
+    pub fn is_mappable(&self) -> bool {
+        self.vaddr.is_some()
+    }
+
+    /// Guest address right after the end of the region
+    pub fn end(&self) -> usize {
+        self.iova + self.length
+    }
+
+    pub fn contains(&self, iova: usize, len: usize) -> bool {
+        iova >= self.iova && iova.checked_add(len).is_some_and(|end| end <= self.end())
+    }
+
+    pub fn overlaps(&self, iova: usize, len: usize) -> bool {
+        iova < self.end() && iova.saturating_add(len) > self.iova
+    }
+}
+
+// Shared between a dma range and the context, revoked once a dma region the range touches
+// is unregistered or the context is dropped, afterwards the range must not touch guest memory
+// or the vfu context anymore
+#[derive(Debug)]
+pub(crate) struct DmaLease {
+    dma_addr: usize,
+    len: usize,
+    revoked: Cell<bool>,
+}
+
+impl DmaLease {
+    fn check(&self) -> Result<()> {
+        ensure!(
+            !self.revoked.get(),
+            "Dma range {:#x}+{:#x} has been revoked, its dma region is no longer registered",
+            self.dma_addr,
+            self.len
+        );
+        Ok(())
+    }
+}
+
+/// Read-only view of an entry of the scatter-gather list describing a dma range
+#[derive(Clone, Debug)]
+pub struct SgEntry {
+    /// Guest address the entry starts at
+    pub dma_addr: usize,
+    /// Index of the dma region in libvfio-user's region table
+    pub region: usize,
+    /// Offset of the entry inside its dma region
+    pub offset: usize,
+    pub length: usize,
+    pub writeable: bool,
+}
+
+impl SgEntry {
+    // dma_sg is private to libvfio-user, its bindings are only trusted if the size matches the
+    // one the library reports, which catches system libraries with a different layout
+    fn layout_matches() -> bool {
+        unsafe { dma_sg_size() == size_of::<private::dma_sg>() }
+    }
+
+    unsafe fn from_ptr(sg: *const dma_sg_t) -> SgEntry {
+        let sg = (sg as *const private::dma_sg).read();
+        SgEntry {
+            dma_addr: sg.dma_addr as usize + sg.offset as usize,
+            region: sg.region as usize,
+            offset: sg.offset as usize,
+            length: sg.length as usize,
+            writeable: sg.writeable,
+        }
+    }
+}
+
 // Debug implemented manually to inspect sgl entries
 pub struct DmaRange {
-    // Vfu context and sgl_buffer is needed for vfu_sg_is_mappable and vfu_sgl_put call
+    // Sgl including vfu context is needed for vfu_sg_is_mappable and vfu_sgl_put call
     // when DmaMapping is dropped
-    ctx: *mut vfu_ctx_t,
-    sgl_buffer: Vec<u8>,
+    sgl: Sgl,
 
-    size: usize,
-    region_count: usize,
+    // Needed to populate sgls for parts of the range
+    dma_addr: usize,
+    max_regions: usize,
+    prot: c_int,
+
+    lease: Rc<DmaLease>,
 }
 
 impl DmaRange {
     pub fn size(&self) -> usize {
-        self.size
+        self.sgl.len
     }
 
     pub fn region_count(&self) -> usize {
-        self.region_count
+        self.sgl.count
+    }
+
+    /// Whether a dma region of the range has been unregistered, all accesses fail afterwards
+    pub fn is_revoked(&self) -> bool {
+        self.lease.revoked.get()
     }
 
-    pub fn read(&mut self) -> Result<Vec<u8>> {
-        let mut buffer = vec![0u8; self.size];
+    /// Entries of the scatter-gather list, one per guest dma region the range touches
+    pub fn sg_entries(&self) -> Result<impl Iterator<Item = SgEntry> + '_> {
+        ensure!(
+            SgEntry::layout_matches(),
+            "Unknown dma_sg_t layout, can not inspect sg entries"
+        );
+
+        Ok(self
+            .sgl
+            .entries()
+            .map(|sg| unsafe { SgEntry::from_ptr(sg) }))
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; self.sgl.len];
         self.read_into(buffer.as_mut_slice())?;
         Ok(buffer)
     }
 
-    pub fn read_into(&mut self, buffer: &mut [u8]) -> Result<()> {
+    pub fn read_into(&self, buffer: &mut [u8]) -> Result<()> {
+        self.lease.check()?;
         ensure!(
-            buffer.len() == self.size,
+            buffer.len() == self.sgl.len,
             "Read buffer must have same size as dma range"
         );
 
-        let ret = unsafe {
-            vfu_sgl_read(
-                self.ctx,
-                self.sgl_buffer.as_mut_ptr() as *mut dma_sg_t,
-                1,
-                buffer.as_mut_ptr() as *mut c_void,
-            )
-        };
-
-        if ret != 0 {
-            let err = Error::last_os_error();
-            return Err(anyhow!("Failed to read from dma range: {}", err));
-        }
-
-        Ok(())
+        unsafe { self.sgl.transfer(buffer.as_mut_ptr(), false) }
+            .map_err(|e| anyhow!("Failed to read from dma range: {}", e))
     }
 
-    pub fn write(&mut self, buffer: &[u8]) -> Result<()> {
+    pub fn write(&self, buffer: &[u8]) -> Result<()> {
+        self.lease.check()?;
         ensure!(
-            buffer.len() == self.size,
+            buffer.len() == self.sgl.len,
             "Must write exact size of dma range"
         );
 
-        let ret = unsafe {
-            vfu_sgl_write(
-                self.ctx,
-                self.sgl_buffer.as_mut_ptr() as *mut dma_sg_t,
-                1,
-                // Intentional cast from const ptr to mut ptr, contents should not change
-                buffer.as_ptr() as *mut c_void,
-            )
-        };
+        // Intentional cast from const ptr to mut ptr, contents should not change
+        unsafe { self.sgl.transfer(buffer.as_ptr() as *mut u8, true) }
+            .map_err(|e| anyhow!("Failed to write to dma range: {}", e))
+    }
 
-        if ret != 0 {
-            let err = Error::last_os_error();
-            return Err(anyhow!("Failed to write to dma range: {}", err));
+    /// Read `buffer.len()` bytes starting at `offset` into the range,
+    /// the access may span several sg entries
+    pub fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<()> {
+        self.transfer_at(offset, buffer.as_mut_ptr(), buffer.len(), false)
+            .map_err(|e| anyhow!("Failed to read from dma range: {}", e))
+    }
+
+    /// Write `buffer` starting at `offset` into the range,
+    /// the access may span several sg entries
+    pub fn write_at(&self, offset: usize, buffer: &[u8]) -> Result<()> {
+        // Intentional cast from const ptr to mut ptr, contents are not changed when writing
+        self.transfer_at(offset, buffer.as_ptr() as *mut u8, buffer.len(), true)
+            .map_err(|e| anyhow!("Failed to write to dma range: {}", e))
+    }
+
+    fn transfer_at(&self, offset: usize, data: *mut u8, len: usize, write: bool) -> Result<()> {
+        self.lease.check()?;
+        ensure!(
+            offset
+                .checked_add(len)
+                .is_some_and(|end| end <= self.sgl.len),
+            "Length + offset exceed dma range size"
+        );
+
+        if len == 0 {
+            return Ok(());
         }
 
-        Ok(())
+        // Populate a sgl covering only the accessed part, entries can only be transferred whole
+        unsafe {
+            let sgl = Sgl::populate(
+                self.sgl.ctx,
+                self.dma_addr + offset,
+                len,
+                self.max_regions,
+                self.prot,
+            )?;
+            sgl.transfer(data, write)
+        }
     }
 
     pub fn is_mappable(&self) -> bool {
-        // Ensure all sgl entries are mappable
-        unsafe {
-            self.sgl_buffer
-                .chunks_exact(dma_sg_size())
-                // Cast from const ptr to mut ptr, should be fine since vfu_sg_is_mappable does not
-                // affect contents (parameter mut because of bindings)
-                .map(|sg| vfu_sg_is_mappable(self.ctx, sg.as_ptr() as *mut dma_sg_t))
-                .all(|b| b)
+        if self.is_revoked() {
+            return false;
         }
+
+        // Ensure all populated sgl entries are mappable
+        self.sgl
+            .entries()
+            // Cast from const ptr to mut ptr, should be fine since vfu_sg_is_mappable does not
+            // affect contents (parameter mut because of bindings)
+            .all(|sg| unsafe { vfu_sg_is_mappable(self.sgl.ctx, sg as *mut dma_sg_t) })
     }
 
     pub fn into_mapping(mut self) -> Result<DmaMapping> {
+        self.lease.check()?;
         ensure!(self.is_mappable(), "Dma range is not mappable.");
 
         let mut iovs: Vec<iovec> = vec![
@@ -104,15 +262,15 @@ impl DmaRange {
                 iov_base: null_mut(),
                 iov_len: 0
             };
-            self.region_count
+            self.sgl.count
         ];
 
         let ret = unsafe {
             vfu_sgl_get(
-                self.ctx,
-                self.sgl_buffer.as_mut_ptr() as *mut dma_sg_t,
+                self.sgl.ctx,
+                self.sgl.buffer.as_mut_ptr() as *mut dma_sg_t,
                 iovs.as_mut_ptr(),
-                self.region_count,
+                self.sgl.count,
                 0,
             )
         };
@@ -137,17 +295,26 @@ pub struct DmaMapping {
 }
 
 impl DmaMapping {
-    pub fn dma(&self, region_index: usize) -> &[u8] {
+    /// Fails once the mapping has been revoked, see [is_revoked](Self::is_revoked)
+    pub fn dma(&self, region_index: usize) -> Result<&[u8]> {
+        self.range.lease.check()?;
         let region = self.mapped_regions[region_index];
-        unsafe { from_raw_parts(region.iov_base as *const u8, region.iov_len) }
+        Ok(unsafe { from_raw_parts(region.iov_base as *const u8, region.iov_len) })
     }
 
-    pub fn dma_mut(&mut self, region_index: usize) -> &mut [u8] {
+    pub fn dma_mut(&mut self, region_index: usize) -> Result<&mut [u8]> {
+        self.range.lease.check()?;
         let region = self.mapped_regions[region_index];
-        unsafe { from_raw_parts_mut(region.iov_base as *mut u8, region.iov_len) }
+        Ok(unsafe { from_raw_parts_mut(region.iov_base as *mut u8, region.iov_len) })
         // We do not need to call vfu_sgl_mark_dirty since we call vfu_sgl_put on drop
     }
 
+    /// Whether a dma region of the mapping has been unregistered by the client,
+    /// libvfio-user unmaps it so all accesses fail afterwards
+    pub fn is_revoked(&self) -> bool {
+        self.range.is_revoked()
+    }
+
     pub fn read_volatile(
         &self, region_index: usize, length: usize, offset: usize,
     ) -> Result<Vec<u8>> {
@@ -159,6 +326,7 @@ impl DmaMapping {
     pub fn read_into_volatile(
         &self, region_index: usize, buffer: &mut [u8], offset: usize,
     ) -> Result<()> {
+        self.range.lease.check()?;
         let region = self.mapped_regions[region_index];
         ensure!(
             buffer.len() + offset <= region.iov_len,
@@ -166,16 +334,14 @@ impl DmaMapping {
         );
 
         unsafe {
-            let ptr = (region.iov_base as *const u8).offset(offset as isize);
-            for i in 0..buffer.len() {
-                buffer[i] = ptr.offset(i as isize).read_volatile();
-            }
+            volatile_copy_from((region.iov_base as *const u8).add(offset), buffer);
         }
 
         Ok(())
     }
 
     pub fn write_volatile(&self, region_index: usize, buffer: &[u8], offset: usize) -> Result<()> {
+        self.range.lease.check()?;
         let region = self.mapped_regions[region_index];
         ensure!(
             buffer.len() + offset <= region.iov_len,
@@ -183,10 +349,7 @@ impl DmaMapping {
         );
 
         unsafe {
-            let ptr = (region.iov_base as *mut u8).offset(offset as isize);
-            for i in 0..buffer.len() {
-                ptr.offset(i as isize).write_volatile(buffer[i]);
-            }
+            volatile_copy_to((region.iov_base as *mut u8).add(offset), buffer);
         }
 
         Ok(())
@@ -214,10 +377,15 @@ impl DmaMapping {
 
 impl Drop for DmaMapping {
     fn drop(&mut self) {
+        // The region is gone, possibly along with the vfu context
+        if self.is_revoked() {
+            return;
+        }
+
         unsafe {
             vfu_sgl_put(
-                self.range.ctx,
-                self.range.sgl_buffer.as_mut_ptr() as *mut dma_sg_t,
+                self.range.sgl.ctx,
+                self.range.sgl.buffer.as_mut_ptr() as *mut dma_sg_t,
                 self.mapped_regions.as_mut_ptr(), // Parameter unused inside vfu_sgl_put
                 self.mapped_regions.len(),
             );
@@ -226,6 +394,53 @@ impl Drop for DmaMapping {
 }
 
 impl DeviceContext {
+    /// Dma regions currently registered by the client, ordered by guest address
+    pub fn dma_regions(&self) -> Vec<DmaRegionInfo> {
+        self.dma_regions.borrow().values().cloned().collect()
+    }
+
+    /// Maximum number of dma regions the client may register, chosen when building libvfio-user
+    pub fn max_dma_regions(&self) -> usize {
+        MAX_DMA_REGIONS
+    }
+
+    /// Registered dma region containing the given guest address
+    pub fn dma_region(&self, iova: usize) -> Option<DmaRegionInfo> {
+        let regions = self.dma_regions.borrow();
+        let (_, region) = regions.range(..=iova).next_back()?;
+        region.contains(iova, 1).then(|| region.clone())
+    }
+
+    /// Whether the guest range is fully covered by registered dma regions,
+    /// the range may span several adjacent regions
+    pub fn dma_contains(&self, iova: usize, len: usize) -> bool {
+        let Some(end) = iova.checked_add(len) else {
+            return false;
+        };
+
+        let regions = self.dma_regions.borrow();
+        let mut covered_until = iova;
+
+        // Start at the region containing iova, or the first region if none starts before it
+        let start = regions
+            .range(..=iova)
+            .next_back()
+            .map(|(start, _)| *start)
+            .unwrap_or(iova);
+
+        for region in regions.range(start..).map(|(_, region)| region) {
+            if covered_until >= end {
+                break;
+            }
+            if region.iova > covered_until {
+                return false;
+            }
+            covered_until = covered_until.max(region.end());
+        }
+
+        covered_until >= end
+    }
+
     pub fn dma_range(
         &self, dma_addr: usize, len: usize, max_regions: usize, read: bool, write: bool,
     ) -> Result<DmaRange> {
@@ -239,58 +454,51 @@ impl DeviceContext {
             "Dma not enabled, have you called .setup_dma(true) during configuration?"
         );
 
-        let mut prot = 0;
-        if read {
-            prot |= 0x1;
-        }
-        if write {
-            prot |= 0x2;
-        }
+        let prot = prot(read, write);
 
         unsafe {
-            // dma_sg_t size is only indirectly available, allocate a buffer and do casts instead
-            let mut sgl_buffer = vec![0u8; dma_sg_size() * max_regions];
+            let sgl = Sgl::populate(self.vfu_ctx, dma_addr, len, max_regions, prot)?;
 
-            let ret = vfu_addr_to_sgl(
-                self.vfu_ctx,
-                dma_addr as vfu_dma_addr_t,
-                len,
-                sgl_buffer.as_mut_ptr() as *mut dma_sg_t,
+            Ok(DmaRange {
+                sgl,
+                dma_addr,
                 max_regions,
                 prot,
-            );
-
-            match ret {
-                0 => {
-                    return Err(anyhow!(
-                        "Failed to populate sgl entries: no entries created"
-                    ));
-                }
-                -1 => {
-                    let err = Error::last_os_error();
-                    return Err(anyhow!("Failed to populate sgl entries: {}", err));
-                }
-                x if x < -1 => {
-                    return Err(anyhow!(
-                        "Failed to populate sgl entries, not enough sg entries available, \
-                        required={}, available={}",
-                        -ret - 1,
-                        max_regions
-                    ));
-                }
-                _ => {}
-            }
-            let region_count = ret as usize;
-
-            Ok(DmaRange {
-                ctx: self.vfu_ctx,
-                sgl_buffer,
-                size: len,
-                region_count,
+                lease: self.lease_dma(dma_addr, len),
             })
         }
     }
 
+    fn lease_dma(&self, dma_addr: usize, len: usize) -> Rc<DmaLease> {
+        let lease = Rc::new(DmaLease {
+            dma_addr,
+            len,
+            revoked: Cell::new(false),
+        });
+
+        let mut leases = self.dma_leases.borrow_mut();
+        leases.retain(|lease| lease.strong_count() > 0);
+        leases.push(Rc::downgrade(&lease));
+        lease
+    }
+
+    /// Revoke live ranges and mappings touching the region, or all if None
+    pub(crate) fn revoke_dma(&self, region: Option<&DmaRegionInfo>) {
+        self.dma_leases.borrow_mut().retain(|lease| {
+            let Some(lease) = lease.upgrade() else {
+                return false;
+            };
+            let affected = match region {
+                Some(region) => region.overlaps(lease.dma_addr, lease.len),
+                None => true,
+            };
+            if affected {
+                lease.revoked.set(true);
+            }
+            !affected
+        });
+    }
+
     pub fn dma_map(
         &self, dma_addr: usize, len: usize, max_regions: usize, read: bool, write: bool,
     ) -> Result<DmaMapping> {
@@ -299,64 +507,140 @@ impl DeviceContext {
     }
 }
 
-// Replica struct of dma_sg in libvfio-user/lib/dma.h
-// dma_sg is not directly exposed, only its size via dma_sg_size()
-// I assume this is because it may change in the future.
-// Therefore this replica should only be used for debugging purposes.
-#[repr(C)]
-#[derive(Debug)]
-struct DmaSgDebug {
-    dma_addr: vfu_dma_addr_t,
-    region: i32,
-    length: u64,
-    offset: u64,
-    writeable: bool,
+fn prot(read: bool, write: bool) -> c_int {
+    let mut prot = 0;
+    if read {
+        prot |= 0x1;
+    }
+    if write {
+        prot |= 0x2;
+    }
+    prot
 }
 
-impl DmaSgDebug {
-    unsafe fn try_from_ptr(sg: *const dma_sg_t) -> Option<DmaSgDebug> {
-        if sg.is_null() {
-            return None;
+// Scatter-gather list populated by vfu_addr_to_sgl
+struct Sgl {
+    ctx: *mut vfu_ctx_t,
+    buffer: Vec<u8>,
+    len: usize,
+    count: usize,
+}
+
+impl Sgl {
+    unsafe fn populate(
+        ctx: *mut vfu_ctx_t, dma_addr: usize, len: usize, max_entries: usize, prot: c_int,
+    ) -> Result<Sgl> {
+        // dma_sg_t size is only indirectly available, allocate a buffer and do casts instead
+        let mut buffer = vec![0u8; dma_sg_size() * max_entries];
+
+        let ret = vfu_addr_to_sgl(
+            ctx,
+            dma_addr as vfu_dma_addr_t,
+            len,
+            buffer.as_mut_ptr() as *mut dma_sg_t,
+            max_entries,
+            prot,
+        );
+
+        match ret {
+            0 => {
+                return Err(anyhow!(
+                    "Failed to populate sgl entries: no entries created"
+                ));
+            }
+            -1 => {
+                let err = Error::last_os_error();
+                return Err(anyhow!("Failed to populate sgl entries: {}", err));
+            }
+            x if x < -1 => {
+                return Err(anyhow!(
+                    "Failed to populate sgl entries, not enough sg entries available, \
+                    required={}, available={}",
+                    -ret - 1,
+                    max_entries
+                ));
+            }
+            _ => {}
+        }
+
+        Ok(Sgl {
+            ctx,
+            buffer,
+            len,
+            count: ret as usize,
+        })
+    }
+
+    // Cast from const ptr to mut ptr, vfu_sgl_read and vfu_sgl_write do not modify the entry
+    // (parameter mut because of bindings)
+    unsafe fn entry(&self, index: usize) -> *mut dma_sg_t {
+        self.buffer[index * dma_sg_size()..].as_ptr() as *mut dma_sg_t
+    }
+
+    /// Pointers to the populated entries, the buffer may have room for more
+    fn entries(&self) -> impl Iterator<Item = *const dma_sg_t> + '_ {
+        self.buffer
+            .chunks_exact(unsafe { dma_sg_size() })
+            .take(self.count)
+            .map(|sg| sg.as_ptr() as *const dma_sg_t)
+    }
+
+    /// Length of each populated entry, which can only be determined for more than one entry
+    /// if the layout of dma_sg_t is known
+    unsafe fn entry_lengths(&self) -> Result<Vec<usize>> {
+        if self.count == 1 {
+            return Ok(vec![self.len]);
         }
 
-        // If sizes don't match the struct probably changed
-        if dma_sg_size() != size_of::<DmaSgDebug>() {
-            return None;
+        ensure!(
+            SgEntry::layout_matches(),
+            "Unknown dma_sg_t layout, can not split access"
+        );
+
+        Ok(self
+            .entries()
+            .map(|sg| SgEntry::from_ptr(sg).length)
+            .collect())
+    }
+
+    /// Read all entries into data or write data to all entries,
+    /// data must be valid for the total length of the sgl
+    unsafe fn transfer(&self, data: *mut u8, write: bool) -> Result<()> {
+        let mut offset = 0;
+
+        // vfu_sgl_read and vfu_sgl_write only support a single entry per call
+        for (i, length) in self.entry_lengths()?.into_iter().enumerate() {
+            let entry_data = data.add(offset) as *mut c_void;
+
+            let ret = if write {
+                vfu_sgl_write(self.ctx, self.entry(i), 1, entry_data)
+            } else {
+                vfu_sgl_read(self.ctx, self.entry(i), 1, entry_data)
+            };
+
+            if ret != 0 {
+                let err = Error::last_os_error();
+                return Err(anyhow!("sg entry {}: {}", i, err));
+            }
+
+            offset += length;
         }
 
-        let sg_debug = (sg as *const DmaSgDebug).read();
-        Some(sg_debug)
+        Ok(())
     }
 }
 
 impl Debug for DmaRange {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        unsafe {
-            // Try to use list of DmaSgDebug instead of just printing the sgl_buffer byte vec
-            let mut sgl = vec![];
-            let mut sgl_formatted = true;
-
-            for sg_chunk in self.sgl_buffer.chunks_exact(dma_sg_size()) {
-                match DmaSgDebug::try_from_ptr(sg_chunk.as_ptr() as *const dma_sg_t) {
-                    Some(sg_debug) => {
-                        sgl.push(sg_debug);
-                    }
-                    None => {
-                        sgl_formatted = false;
-                        break;
-                    }
-                }
-            }
-
-            let mut format = f.debug_struct("DmaMapping");
-            if sgl_formatted {
-                format.field("sgl", &sgl)
-            } else {
-                format.field("sgl_buffer", &self.sgl_buffer)
-            }
-            .field("size", &self.size)
-            .field("region_count", &self.region_count)
-            .finish()
+        // Try to use list of SgEntry instead of just printing the sgl buffer byte vec
+        let mut format = f.debug_struct("DmaRange");
+        match self.sg_entries() {
+            Ok(entries) => format.field("sgl", &entries.collect::<Vec<_>>()),
+            Err(_) => format.field("sgl_buffer", &self.sgl.buffer),
         }
+        .field("dma_addr", &self.dma_addr)
+        .field("size", &self.sgl.len)
+        .field("region_count", &self.sgl.count)
+        .finish()
     }
 }
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::os::raw::c_int;
+use std::rc::Rc;
+
+use anyhow::{anyhow, Result};
+
+use crate::dma::{prot, DmaMapping, DmaRegionInfo};
+use crate::DeviceContext;
+
+/// Counters of the dma mapping cache, see [DeviceContext::dma_map_cached]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DmaMapCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Mappings dropped because the client unregistered their dma region
+    pub invalidations: u64,
+    /// Mappings currently cached
+    pub entries: usize,
+}
+
+// Live mappings by (guest address, length, protection)
+#[derive(Debug, Default)]
+pub(crate) struct DmaMapCache {
+    mappings: HashMap<(usize, usize, c_int), Rc<DmaMapping>>,
+    stats: DmaMapCacheStats,
+}
+
+impl DmaMapCache {
+    /// Drop all mappings overlapping the region, must happen before libvfio-user unmaps it
+    pub(crate) fn invalidate(&mut self, region: &DmaRegionInfo) {
+        let before = self.mappings.len();
+        self.mappings
+            .retain(|(dma_addr, len, _), _| !region.overlaps(*dma_addr, *len));
+        self.stats.invalidations += (before - self.mappings.len()) as u64;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.mappings.clear();
+    }
+
+    fn lookup(&mut self, key: &(usize, usize, c_int)) -> Option<Rc<DmaMapping>> {
+        match self.mappings.get(key) {
+            Some(mapping) => {
+                self.stats.hits += 1;
+                Some(mapping.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+}
+
+impl DeviceContext {
+    /// Like [dma_map](Self::dma_map), but reuses a live mapping of the exact same range.
+    /// Requires `.dma_map_cache(true)` during configuration.
+    ///
+    /// Cached mappings are invalidated once their dma region is unregistered, devices must drop
+    /// their own clones in [Device::dma_range_removed](crate::Device::dma_range_removed) too.
+    pub fn dma_map_cached(
+        &self, dma_addr: usize, len: usize, max_regions: usize, read: bool, write: bool,
+    ) -> Result<Rc<DmaMapping>> {
+        let cache = self.dma_map_cache.as_ref().ok_or_else(|| {
+            anyhow!("Dma map cache not enabled, have you called .dma_map_cache(true) during configuration?")
+        })?;
+
+        let key = (dma_addr, len, prot(read, write));
+        if let Some(mapping) = cache.borrow_mut().lookup(&key) {
+            return Ok(mapping);
+        }
+
+        let mapping = Rc::new(self.dma_map(dma_addr, len, max_regions, read, write)?);
+        cache.borrow_mut().mappings.insert(key, mapping.clone());
+        Ok(mapping)
+    }
+
+    /// Statistics of the dma mapping cache, None if it is not enabled
+    pub fn dma_map_cache_stats(&self) -> Option<DmaMapCacheStats> {
+        let cache = self.dma_map_cache.as_ref()?.borrow();
+        Some(DmaMapCacheStats {
+            entries: cache.mappings.len(),
+            ..cache.stats
+        })
+    }
+
+    /// Drop all cached mappings, e.g. on device reset
+    pub fn clear_dma_map_cache(&self) {
+        if let Some(cache) = &self.dma_map_cache {
+            cache.borrow_mut().clear();
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::os::raw::{c_int, c_void};
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+use anyhow::{anyhow, ensure, Result};
+
+use crate::ffi::iovec;
+
+use crate::dma::DmaMapping;
+
+// Maximum number of iovecs per preadv/pwritev call on Linux, UIO_MAXIOV
+const IOV_MAX: usize = 1024;
+
+impl DmaMapping {
+    /// Mapped regions for vectored writes, e.g. [Write::write_vectored]
+    pub fn as_io_slices(&self) -> Result<Vec<IoSlice<'_>>> {
+        self.range.lease.check()?;
+        Ok(self
+            .mapped_regions
+            .iter()
+            .map(|iov| {
+                IoSlice::new(unsafe { from_raw_parts(iov.iov_base as *const u8, iov.iov_len) })
+            })
+            .collect())
+    }
+
+    /// Mapped regions for vectored reads, e.g. [Read::read_vectored]
+    pub fn as_io_slices_mut(&mut self) -> Result<Vec<IoSliceMut<'_>>> {
+        self.range.lease.check()?;
+        Ok(self
+            .mapped_regions
+            .iter()
+            .map(|iov| {
+                IoSliceMut::new(unsafe { from_raw_parts_mut(iov.iov_base as *mut u8, iov.iov_len) })
+            })
+            .collect())
+    }
+
+    /// Fill the mapping from a file starting at the file offset with preadv,
+    /// returns the number of bytes read which is only short at the end of the file
+    pub fn read_from_file_at(&mut self, file: &impl AsFd, offset: u64) -> Result<usize> {
+        check_writable(self.range.prot)?;
+        self.range.lease.check()?;
+        read_file_at(&self.mapped_regions, file.as_fd().as_raw_fd(), offset)
+            .map_err(|err| anyhow!("Failed to read from file: {}", err))
+    }
+
+    /// Write the whole mapping to a file starting at the file offset with pwritev
+    pub fn write_to_file_at(&self, file: &impl AsFd, offset: u64) -> Result<usize> {
+        self.range.lease.check()?;
+        let written = write_file_at(&self.mapped_regions, file.as_fd().as_raw_fd(), offset)
+            .map_err(|err| anyhow!("Failed to write to file: {}", err))?;
+
+        self.check_complete(written, "write to file")
+    }
+
+    /// Fill the mapping from a reader such as a socket,
+    /// returns the number of bytes read which is only short at end of stream
+    pub fn read_from(&mut self, reader: &mut impl Read) -> Result<usize> {
+        check_writable(self.range.prot)?;
+        self.transfer_vectored(|iovecs, _| {
+            let mut slices: Vec<IoSliceMut> = iovecs
+                .iter()
+                .map(|iov| {
+                    IoSliceMut::new(unsafe {
+                        from_raw_parts_mut(iov.iov_base as *mut u8, iov.iov_len)
+                    })
+                })
+                .collect();
+            reader.read_vectored(&mut slices)
+        })
+        .map_err(|err| anyhow!("Failed to read: {}", err))
+    }
+
+    /// Write the whole mapping to a writer such as a socket
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<usize> {
+        let written = self
+            .transfer_vectored(|iovecs, _| {
+                let slices: Vec<IoSlice> = iovecs
+                    .iter()
+                    .map(|iov| {
+                        IoSlice::new(unsafe {
+                            from_raw_parts(iov.iov_base as *const u8, iov.iov_len)
+                        })
+                    })
+                    .collect();
+                writer.write_vectored(&slices)
+            })
+            .map_err(|err| anyhow!("Failed to write: {}", err))?;
+
+        self.check_complete(written, "write")
+    }
+
+    fn transfer_vectored(
+        &self, transfer: impl FnMut(&[iovec], usize) -> std::io::Result<usize>,
+    ) -> Result<usize> {
+        self.range.lease.check()?;
+        Ok(transfer_iovecs(&self.mapped_regions, transfer)?)
+    }
+
+    fn check_complete(&self, transferred: usize, action: &str) -> Result<usize> {
+        if transferred < self.total_length() {
+            return Err(anyhow!(
+                "Failed to {}: {}",
+                action,
+                Error::from(ErrorKind::WriteZero)
+            ));
+        }
+        Ok(transferred)
+    }
+}
+
+// The client may have mapped the range read-only, writing to it would fault
+fn check_writable(prot: c_int) -> Result<()> {
+    ensure!(
+        prot & libc::PROT_WRITE != 0,
+        "Failed to read into mapping: dma range is not writable"
+    );
+    Ok(())
+}
+
+fn read_file_at(regions: &[iovec], fd: RawFd, offset: u64) -> std::io::Result<usize> {
+    transfer_iovecs(regions, |iovecs, done| {
+        cvt(unsafe {
+            libc::preadv(
+                fd,
+                iovecs.as_ptr() as *const libc::iovec,
+                iovecs.len() as i32,
+                (offset + done as u64) as libc::off_t,
+            )
+        })
+    })
+}
+
+fn write_file_at(regions: &[iovec], fd: RawFd, offset: u64) -> std::io::Result<usize> {
+    transfer_iovecs(regions, |iovecs, done| {
+        cvt(unsafe {
+            libc::pwritev(
+                fd,
+                iovecs.as_ptr() as *const libc::iovec,
+                iovecs.len() as i32,
+                (offset + done as u64) as libc::off_t,
+            )
+        })
+    })
+}
+
+// Call the transfer function with the not yet transferred part of the regions, at most
+// IOV_MAX iovecs at a time, until everything is transferred or it returns 0,
+// retrying if interrupted
+fn transfer_iovecs(
+    regions: &[iovec], mut transfer: impl FnMut(&[iovec], usize) -> std::io::Result<usize>,
+) -> std::io::Result<usize> {
+    let total: usize = regions.iter().map(|iov| iov.iov_len).sum();
+    let mut done = 0;
+
+    while done < total {
+        match transfer(&remaining_iovecs(regions, done), done) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(done)
+}
+
+fn remaining_iovecs(regions: &[iovec], mut skip: usize) -> Vec<iovec> {
+    let mut iovecs = Vec::with_capacity(regions.len().min(IOV_MAX));
+    for iov in regions {
+        if iovecs.len() == IOV_MAX {
+            break;
+        }
+        if skip >= iov.iov_len {
+            skip -= iov.iov_len;
+            continue;
+        }
+        iovecs.push(iovec {
+            iov_base: unsafe { (iov.iov_base as *mut u8).add(skip) } as *mut c_void,
+            iov_len: iov.iov_len - skip,
+        });
+        skip = 0;
+    }
+    iovecs
+}
+
+fn cvt(ret: isize) -> std::io::Result<usize> {
+    match ret {
+        ..=-1 => Err(Error::last_os_error()),
+        _ => Ok(ret as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use std::thread;
+
+    use super::*;
+
+    fn memfd(contents: &[u8]) -> File {
+        let name = CString::new("vfio-user-io-test").unwrap();
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(fd >= 0, "{}", Error::last_os_error());
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(contents).unwrap();
+        file
+    }
+
+    fn pipe() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    // Split the buffer into segments like the regions of a fragmented mapping
+    fn segments(buffer: &mut [u8], len: usize) -> Vec<iovec> {
+        buffer
+            .chunks_mut(len)
+            .map(|chunk| iovec {
+                iov_base: chunk.as_mut_ptr() as *mut c_void,
+                iov_len: chunk.len(),
+            })
+            .collect()
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn read_file_more_than_iov_max_segments() {
+        let data = pattern(3000);
+        let file = memfd(&data);
+
+        let mut buffer = vec![0u8; 2990];
+        let regions = segments(&mut buffer, 1);
+        assert!(regions.len() > IOV_MAX);
+
+        assert_eq!(read_file_at(&regions, file.as_raw_fd(), 10).unwrap(), 2990);
+        assert_eq!(buffer, data[10..]);
+    }
+
+    #[test]
+    fn write_file_more_than_iov_max_segments() {
+        let mut data = pattern(5000);
+        let regions = segments(&mut data, 2);
+        assert!(regions.len() > IOV_MAX);
+
+        let mut file = memfd(&[]);
+        assert_eq!(write_file_at(&regions, file.as_raw_fd(), 16).unwrap(), 5000);
+
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents[..16], [0; 16]);
+        assert_eq!(contents[16..], data);
+    }
+
+    #[test]
+    fn read_file_stops_at_end_of_file() {
+        let data = pattern(100);
+        let file = memfd(&data);
+
+        let mut buffer = vec![0xffu8; 300];
+        let regions = segments(&mut buffer, 64);
+
+        assert_eq!(read_file_at(&regions, file.as_raw_fd(), 0).unwrap(), 100);
+        assert_eq!(buffer[..100], data);
+        assert!(buffer[100..].iter().all(|byte| *byte == 0xff));
+    }
+
+    #[test]
+    fn partial_transfers_are_resumed() {
+        let data = pattern(4000);
+        let (reader, writer) = pipe();
+
+        // Chunks which end in the middle of segments
+        let chunks = data.clone();
+        let writer = thread::spawn(move || {
+            let mut writer = File::from(writer);
+            for chunk in chunks.chunks(1000) {
+                writer.write_all(chunk).unwrap();
+                thread::sleep(std::time::Duration::from_millis(10));
+            }
+        });
+
+        let mut buffer = vec![0u8; 4000];
+        let regions = segments(&mut buffer, 7);
+        let mut calls = 0;
+        let read = transfer_iovecs(&regions, |iovecs, _| {
+            calls += 1;
+            cvt(unsafe {
+                libc::readv(
+                    reader.as_raw_fd(),
+                    iovecs.as_ptr() as *const libc::iovec,
+                    iovecs.len() as i32,
+                )
+            })
+        })
+        .unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(read, 4000);
+        assert_eq!(buffer, data);
+        assert!(calls > 1);
+    }
+
+    #[test]
+    fn interrupted_transfers_are_retried() {
+        let data = pattern(64);
+        let file = memfd(&data);
+
+        let mut buffer = vec![0u8; 64];
+        let regions = segments(&mut buffer, 16);
+        let mut interrupted = 0;
+        let read = transfer_iovecs(&regions, |iovecs, done| {
+            if interrupted < 2 {
+                interrupted += 1;
+                return Err(Error::from_raw_os_error(libc::EINTR));
+            }
+            cvt(unsafe {
+                libc::preadv(
+                    file.as_raw_fd(),
+                    iovecs.as_ptr() as *const libc::iovec,
+                    iovecs.len() as i32,
+                    done as libc::off_t,
+                )
+            })
+        })
+        .unwrap();
+
+        assert_eq!(interrupted, 2);
+        assert_eq!(read, 64);
+        assert_eq!(buffer, data);
+    }
+
+    #[test]
+    fn errors_are_returned() {
+        let (reader, _writer) = pipe();
+        let mut buffer = vec![0u8; 16];
+        let regions = segments(&mut buffer, 8);
+
+        // Pipes cannot be read at an offset
+        let err = read_file_at(&regions, reader.as_raw_fd(), 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESPIPE));
+    }
+
+    #[test]
+    fn read_only_ranges_are_rejected() {
+        assert!(check_writable(libc::PROT_READ).is_err());
+        assert!(check_writable(libc::PROT_WRITE).is_ok());
+        assert!(check_writable(libc::PROT_READ | libc::PROT_WRITE).is_ok());
+    }
+
+    #[test]
+    fn remaining_iovecs_skips_and_caps() {
+        let mut buffer = vec![0u8; 3000];
+        let regions = segments(&mut buffer, 2);
+
+        let remaining = remaining_iovecs(&regions, 5);
+        assert_eq!(remaining.len(), IOV_MAX);
+        assert_eq!(remaining[0].iov_len, 1);
+        assert_eq!(remaining[0].iov_base, unsafe { regions[2].iov_base.add(1) });
+        assert_eq!(remaining[1].iov_base, regions[3].iov_base);
+
+        assert!(remaining_iovecs(&regions, 3000).is_empty());
+    }
+}
@@ -0,0 +1,385 @@
+use std::fmt::{Debug, Formatter};
+use std::ptr::{read_volatile, write_volatile};
+
+use anyhow::{ensure, Result};
+use bytemuck::{Pod, Zeroable};
+
+use crate::dma::DmaMapping;
+use crate::DeviceContext;
+
+/// Copy from guest memory using the widest naturally aligned volatile accesses possible,
+/// so aligned fields of up to 8 bytes are never torn by a concurrent guest access
+pub(super) unsafe fn volatile_copy_from(src: *const u8, buffer: &mut [u8]) {
+    let mut i = 0;
+    while i < buffer.len() {
+        let ptr = src.add(i);
+        let remaining = buffer.len() - i;
+
+        let width = access_width(ptr as usize, remaining);
+        match width {
+            8 => buffer[i..i + 8].copy_from_slice(&read_volatile(ptr as *const u64).to_ne_bytes()),
+            4 => buffer[i..i + 4].copy_from_slice(&read_volatile(ptr as *const u32).to_ne_bytes()),
+            2 => buffer[i..i + 2].copy_from_slice(&read_volatile(ptr as *const u16).to_ne_bytes()),
+            _ => buffer[i] = read_volatile(ptr),
+        }
+        i += width;
+    }
+}
+
+/// Copy into guest memory, counterpart of [volatile_copy_from]
+pub(super) unsafe fn volatile_copy_to(dst: *mut u8, buffer: &[u8]) {
+    let mut i = 0;
+    while i < buffer.len() {
+        let ptr = dst.add(i);
+        let remaining = buffer.len() - i;
+
+        let width = access_width(ptr as usize, remaining);
+        let bytes = &buffer[i..i + width];
+        match width {
+            8 => write_volatile(
+                ptr as *mut u64,
+                u64::from_ne_bytes(bytes.try_into().unwrap()),
+            ),
+            4 => write_volatile(
+                ptr as *mut u32,
+                u32::from_ne_bytes(bytes.try_into().unwrap()),
+            ),
+            2 => write_volatile(
+                ptr as *mut u16,
+                u16::from_ne_bytes(bytes.try_into().unwrap()),
+            ),
+            _ => write_volatile(ptr, bytes[0]),
+        }
+        i += width;
+    }
+}
+
+fn access_width(address: usize, remaining: usize) -> usize {
+    [8, 4, 2]
+        .into_iter()
+        .find(|width| address & (width - 1) == 0 && remaining >= *width)
+        .unwrap_or(1)
+}
+
+impl DmaMapping {
+    /// Volatile read at an offset relative to the start of the mapping,
+    /// the buffer may span several mapped regions
+    pub fn read_at_volatile(&self, offset: usize, buffer: &mut [u8]) -> Result<()> {
+        self.check_bounds(offset, buffer.len())?;
+
+        let mut done = 0;
+        for (region_index, region_offset, length) in self.chunks(offset, buffer.len()) {
+            self.read_into_volatile(
+                region_index,
+                &mut buffer[done..done + length],
+                region_offset,
+            )?;
+            done += length;
+        }
+        Ok(())
+    }
+
+    /// Volatile write at an offset relative to the start of the mapping,
+    /// the buffer may span several mapped regions
+    pub fn write_at_volatile(&self, offset: usize, buffer: &[u8]) -> Result<()> {
+        self.check_bounds(offset, buffer.len())?;
+
+        let mut done = 0;
+        for (region_index, region_offset, length) in self.chunks(offset, buffer.len()) {
+            self.write_volatile(region_index, &buffer[done..done + length], region_offset)?;
+            done += length;
+        }
+        Ok(())
+    }
+
+    pub fn read_obj<T: Pod>(&self, offset: usize) -> Result<T> {
+        let mut value = T::zeroed();
+        self.read_at_volatile(offset, bytemuck::bytes_of_mut(&mut value))?;
+        Ok(value)
+    }
+
+    pub fn write_obj<T: Pod>(&self, offset: usize, value: &T) -> Result<()> {
+        self.write_at_volatile(offset, bytemuck::bytes_of(value))
+    }
+
+    pub fn read_slice<T: Pod>(&self, offset: usize, values: &mut [T]) -> Result<()> {
+        self.read_at_volatile(offset, bytemuck::cast_slice_mut(values))
+    }
+
+    pub fn write_slice<T: Pod>(&self, offset: usize, values: &[T]) -> Result<()> {
+        self.write_at_volatile(offset, bytemuck::cast_slice(values))
+    }
+
+    fn check_bounds(&self, offset: usize, len: usize) -> Result<()> {
+        ensure!(
+            offset
+                .checked_add(len)
+                .is_some_and(|end| end <= self.total_length()),
+            "Length + offset exceed mapping length"
+        );
+        Ok(())
+    }
+
+    // Split a range of the mapping into (region index, offset inside region, length) chunks
+    fn chunks(&self, mut offset: usize, mut len: usize) -> Vec<(usize, usize, usize)> {
+        let mut chunks = Vec::new();
+        for (region_index, region_length) in self.lengths().into_iter().enumerate() {
+            if len == 0 {
+                break;
+            }
+            if offset >= region_length {
+                offset -= region_length;
+                continue;
+            }
+
+            let length = len.min(region_length - offset);
+            chunks.push((region_index, offset, length));
+            offset = 0;
+            len -= length;
+        }
+        chunks
+    }
+}
+
+impl DeviceContext {
+    /// Read a plain old data object from guest memory, e.g. a descriptor.
+    /// Directly mapped when possible, otherwise transferred via messages to the client
+    pub fn read_obj<T: Pod>(&self, dma_addr: usize) -> Result<T> {
+        let mut value = T::zeroed();
+        self.guest_read(dma_addr, bytemuck::bytes_of_mut(&mut value))?;
+        Ok(value)
+    }
+
+    /// Write a plain old data object to guest memory
+    pub fn write_obj<T: Pod>(&self, dma_addr: usize, value: &T) -> Result<()> {
+        self.guest_write(dma_addr, bytemuck::bytes_of(value))
+    }
+
+    pub fn read_slice<T: Pod>(&self, dma_addr: usize, values: &mut [T]) -> Result<()> {
+        self.guest_read(dma_addr, bytemuck::cast_slice_mut(values))
+    }
+
+    pub fn write_slice<T: Pod>(&self, dma_addr: usize, values: &[T]) -> Result<()> {
+        self.guest_write(dma_addr, bytemuck::cast_slice(values))
+    }
+
+    fn guest_read(&self, dma_addr: usize, buffer: &mut [u8]) -> Result<()> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let max_regions = self.dma_region_count(dma_addr, buffer.len())?;
+        let range = self.dma_range(dma_addr, buffer.len(), max_regions, true, false)?;
+        if range.is_mappable() {
+            range.into_mapping()?.read_at_volatile(0, buffer)
+        } else {
+            range.read_into(buffer)
+        }
+    }
+
+    fn guest_write(&self, dma_addr: usize, buffer: &[u8]) -> Result<()> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let max_regions = self.dma_region_count(dma_addr, buffer.len())?;
+        let range = self.dma_range(dma_addr, buffer.len(), max_regions, false, true)?;
+        if range.is_mappable() {
+            range.into_mapping()?.write_at_volatile(0, buffer)
+        } else {
+            range.write(buffer)
+        }
+    }
+
+    // Number of registered dma regions the guest range spans
+    fn dma_region_count(&self, dma_addr: usize, len: usize) -> Result<usize> {
+        ensure!(
+            self.dma_contains(dma_addr, len),
+            "Guest range {:#x}+{:#x} is not covered by dma regions",
+            dma_addr,
+            len
+        );
+
+        // Regions are keyed by iova, only those starting before the end can overlap
+        let end = dma_addr + len;
+        let count = self
+            .dma_regions
+            .borrow()
+            .range(..end)
+            .filter(|(_, region)| region.end() > dma_addr)
+            .count();
+        Ok(count)
+    }
+}
+
+macro_rules! endian_type {
+    ($name:ident, $native:ty, $to:ident, $from:ident, $doc:literal) => {
+        #[doc = $doc]
+        #[repr(transparent)]
+        #[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Pod, Zeroable)]
+        pub struct $name($native);
+
+        impl $name {
+            pub fn new(value: $native) -> Self {
+                $name(value.$to())
+            }
+
+            pub fn get(self) -> $native {
+                <$native>::$from(self.0)
+            }
+        }
+
+        impl From<$native> for $name {
+            fn from(value: $native) -> Self {
+                $name::new(value)
+            }
+        }
+
+        impl From<$name> for $native {
+            fn from(value: $name) -> Self {
+                value.get()
+            }
+        }
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}({:#x})", stringify!($name), self.get())
+            }
+        }
+    };
+}
+
+endian_type!(
+    Le16,
+    u16,
+    to_le,
+    from_le,
+    "Little endian u16 as stored in guest memory"
+);
+endian_type!(
+    Le32,
+    u32,
+    to_le,
+    from_le,
+    "Little endian u32 as stored in guest memory"
+);
+endian_type!(
+    Le64,
+    u64,
+    to_le,
+    from_le,
+    "Little endian u64 as stored in guest memory"
+);
+endian_type!(
+    Be16,
+    u16,
+    to_be,
+    from_be,
+    "Big endian u16 as stored in guest memory"
+);
+endian_type!(
+    Be32,
+    u32,
+    to_be,
+    from_be,
+    "Big endian u32 as stored in guest memory"
+);
+endian_type!(
+    Be64,
+    u64,
+    to_be,
+    from_be,
+    "Big endian u64 as stored in guest memory"
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_width_follows_alignment() {
+        assert_eq!(access_width(0x1000, 16), 8);
+        assert_eq!(access_width(0x1004, 16), 4);
+        assert_eq!(access_width(0x1002, 16), 2);
+        assert_eq!(access_width(0x1001, 16), 1);
+        // Limited by the remaining length
+        assert_eq!(access_width(0x1000, 7), 4);
+        assert_eq!(access_width(0x1000, 3), 2);
+        assert_eq!(access_width(0x1000, 1), 1);
+    }
+
+    // Widths of the accesses a copy of len bytes starting at address is split into
+    fn access_widths(mut address: usize, len: usize) -> Vec<usize> {
+        let mut widths = Vec::new();
+        let end = address + len;
+        while address < end {
+            let width = access_width(address, end - address);
+            widths.push(width);
+            address += width;
+        }
+        widths
+    }
+
+    #[test]
+    fn unaligned_copy_is_split() {
+        assert_eq!(access_widths(0x1000, 16), [8, 8]);
+        assert_eq!(access_widths(0x1001, 16), [1, 2, 4, 8, 1]);
+        assert_eq!(access_widths(0x1003, 6), [1, 4, 1]);
+        assert_eq!(access_widths(0x1006, 4), [2, 2]);
+    }
+
+    #[test]
+    fn volatile_copy_round_trip() {
+        let memory: Vec<u64> = vec![0; 4];
+        let base = memory.as_ptr() as *mut u8;
+        let data: Vec<u8> = (1..=23).collect();
+
+        // Every start offset hits a different split of the copy
+        for offset in 0..8 {
+            unsafe { volatile_copy_to(base.add(offset), &data) };
+
+            let mut buffer = vec![0u8; data.len()];
+            unsafe { volatile_copy_from(base.add(offset), &mut buffer) };
+            assert_eq!(buffer, data, "offset {}", offset);
+
+            let bytes = bytemuck::cast_slice::<u64, u8>(&memory);
+            assert_eq!(&bytes[offset..offset + data.len()], &data[..]);
+        }
+    }
+
+    #[test]
+    fn little_endian_layout() {
+        assert_eq!(bytemuck::bytes_of(&Le16::new(0x0102)), [0x02, 0x01]);
+        assert_eq!(
+            bytemuck::bytes_of(&Le32::new(0x01020304)),
+            [0x04, 0x03, 0x02, 0x01]
+        );
+        assert_eq!(
+            bytemuck::bytes_of(&Le64::new(0x0102030405060708)),
+            [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]
+        );
+        assert_eq!(Le32::new(0x01020304).get(), 0x01020304);
+        assert_eq!(
+            bytemuck::pod_read_unaligned::<Le16>(&[0x34, 0x12]).get(),
+            0x1234
+        );
+    }
+
+    #[test]
+    fn big_endian_layout() {
+        assert_eq!(bytemuck::bytes_of(&Be16::new(0x0102)), [0x01, 0x02]);
+        assert_eq!(
+            bytemuck::bytes_of(&Be32::new(0x01020304)),
+            [0x01, 0x02, 0x03, 0x04]
+        );
+        assert_eq!(
+            bytemuck::bytes_of(&Be64::new(0x0102030405060708)),
+            [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]
+        );
+        assert_eq!(Be64::new(0x0102030405060708).get(), 0x0102030405060708);
+        assert_eq!(
+            bytemuck::pod_read_unaligned::<Be16>(&[0x12, 0x34]).get(),
+            0x1234
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! Rings of fixed-size descriptors in guest memory, indexed by head/tail doorbells.
+//! One slot is always kept free, so `head == tail` means empty and never full.
+
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::rc::Rc;
+use std::sync::atomic::{fence, Ordering};
+
+use anyhow::{ensure, Result};
+use bytemuck::Pod;
+
+use crate::DeviceContext;
+
+// Upper bound for entries, as large as the queues of common devices (e.g. NVMe) get
+const MAX_RING_ENTRIES: u32 = 1 << 16;
+
+/// Location and shape of a ring in guest memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RingGeometry {
+    /// Guest address of the first descriptor
+    pub base: usize,
+    /// Number of descriptor slots
+    pub entries: u32,
+    /// Distance between two descriptors in bytes, at least the descriptor size
+    pub stride: usize,
+}
+
+impl RingGeometry {
+    /// Densely packed ring of descriptors of type D
+    pub fn new<D>(base: usize, entries: u32) -> Self {
+        RingGeometry {
+            base,
+            entries,
+            stride: size_of::<D>(),
+        }
+    }
+
+    /// Size of the whole ring in guest memory
+    pub fn size(&self) -> usize {
+        self.entries as usize * self.stride
+    }
+
+    fn address(&self, index: u32) -> usize {
+        self.base + index as usize * self.stride
+    }
+
+    fn next(&self, index: u32) -> u32 {
+        (index + 1) % self.entries
+    }
+
+    // Slots from index `from` up to `to`, wrapping around the end of the ring
+    fn distance(&self, from: u32, to: u32) -> u32 {
+        let entries = self.entries as u64;
+        ((to as u64 + entries - from as u64) % entries) as u32
+    }
+
+    fn validate<D>(&self) -> Result<()> {
+        ensure!(self.entries >= 2, "Ring needs at least 2 entries");
+        ensure!(
+            self.entries <= MAX_RING_ENTRIES,
+            "Ring has {} entries, at most {} are supported",
+            self.entries,
+            MAX_RING_ENTRIES
+        );
+        ensure!(
+            self.stride >= size_of::<D>(),
+            "Ring stride {} is smaller than the descriptor size {}",
+            self.stride,
+            size_of::<D>()
+        );
+        let size = (self.entries as usize).checked_mul(self.stride);
+        ensure!(
+            size.and_then(|size| self.base.checked_add(size)).is_some(),
+            "Ring at {:#x} overflows the address space",
+            self.base
+        );
+        Ok(())
+    }
+
+    fn validate_index(&self, index: u32) -> Result<()> {
+        ensure!(
+            index < self.entries,
+            "Ring index {} out of bounds, ring has {} entries",
+            index,
+            self.entries
+        );
+        Ok(())
+    }
+}
+
+/// Ring the guest produces descriptors into and the device consumes from
+pub struct ConsumerRing<D: Pod> {
+    ctx: Rc<DeviceContext>,
+    geometry: RingGeometry,
+    head: u32,
+    tail: u32,
+    irq: Option<u32>,
+    _descriptor: PhantomData<D>,
+}
+
+impl<D: Pod> ConsumerRing<D> {
+    pub fn new(ctx: Rc<DeviceContext>, geometry: RingGeometry) -> Result<Self> {
+        geometry.validate::<D>()?;
+        Ok(ConsumerRing {
+            ctx,
+            geometry,
+            head: 0,
+            tail: 0,
+            irq: None,
+            _descriptor: PhantomData,
+        })
+    }
+
+    /// Raise the given interrupt subindex in [complete](Self::complete)
+    pub fn with_interrupt(mut self, subindex: u32) -> Self {
+        self.irq = Some(subindex);
+        self
+    }
+
+    pub fn geometry(&self) -> RingGeometry {
+        self.geometry
+    }
+
+    /// Index of the next descriptor the device consumes, e.g. for a head register in a BAR
+    pub fn head(&self) -> u32 {
+        self.head
+    }
+
+    pub fn tail(&self) -> u32 {
+        self.tail
+    }
+
+    /// Tail doorbell written by the guest, descriptors up to the tail are ready
+    pub fn set_tail(&mut self, tail: u32) -> Result<()> {
+        self.geometry.validate_index(tail)?;
+        self.tail = tail;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    /// Number of descriptors available to consume
+    pub fn pending(&self) -> u32 {
+        self.geometry.distance(self.head, self.tail)
+    }
+
+    /// Next descriptor without consuming it
+    pub fn peek(&self) -> Result<Option<D>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+
+        // The guest wrote the descriptor before ringing the doorbell we observed
+        fence(Ordering::Acquire);
+        let descriptor = self.ctx.read_obj(self.geometry.address(self.head))?;
+        Ok(Some(descriptor))
+    }
+
+    /// Consume the next descriptor, the head only advances if it could be read
+    pub fn pop(&mut self) -> Result<Option<D>> {
+        let descriptor = self.peek()?;
+        if descriptor.is_some() {
+            self.head = self.geometry.next(self.head);
+        }
+        Ok(descriptor)
+    }
+
+    /// Signal the guest that descriptors have been consumed
+    pub fn complete(&self) -> Result<()> {
+        if let Some(subindex) = self.irq {
+            self.ctx.trigger_irq(subindex)?;
+        }
+        Ok(())
+    }
+
+    /// Return to the initial empty state, e.g. on device reset
+    pub fn reset(&mut self) {
+        self.head = 0;
+        self.tail = 0;
+    }
+}
+
+impl<D: Pod> Iterator for ConsumerRing<D> {
+    type Item = Result<D>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pop().transpose()
+    }
+}
+
+/// Ring the device produces descriptors into and the guest consumes from
+pub struct ProducerRing<D: Pod> {
+    ctx: Rc<DeviceContext>,
+    geometry: RingGeometry,
+    head: u32,
+    tail: u32,
+    irq: Option<u32>,
+    _descriptor: PhantomData<D>,
+}
+
+impl<D: Pod> ProducerRing<D> {
+    pub fn new(ctx: Rc<DeviceContext>, geometry: RingGeometry) -> Result<Self> {
+        geometry.validate::<D>()?;
+        Ok(ProducerRing {
+            ctx,
+            geometry,
+            head: 0,
+            tail: 0,
+            irq: None,
+            _descriptor: PhantomData,
+        })
+    }
+
+    /// Raise the given interrupt subindex in [notify](Self::notify)
+    pub fn with_interrupt(mut self, subindex: u32) -> Self {
+        self.irq = Some(subindex);
+        self
+    }
+
+    pub fn geometry(&self) -> RingGeometry {
+        self.geometry
+    }
+
+    pub fn head(&self) -> u32 {
+        self.head
+    }
+
+    /// Index of the next slot the device produces into, e.g. for a tail register in a BAR
+    pub fn tail(&self) -> u32 {
+        self.tail
+    }
+
+    /// Head doorbell written by the guest, descriptors before the head have been consumed
+    pub fn set_head(&mut self, head: u32) -> Result<()> {
+        self.geometry.validate_index(head)?;
+        self.head = head;
+        Ok(())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.geometry.next(self.tail) == self.head
+    }
+
+    /// Number of free slots
+    pub fn available(&self) -> u32 {
+        self.geometry
+            .distance(self.geometry.next(self.tail), self.head)
+    }
+
+    /// Write a descriptor into the next free slot, returns false if the ring is full
+    pub fn push(&mut self, descriptor: &D) -> Result<bool> {
+        if self.is_full() {
+            return Ok(false);
+        }
+
+        self.ctx
+            .write_obj(self.geometry.address(self.tail), descriptor)?;
+        self.tail = self.geometry.next(self.tail);
+        Ok(true)
+    }
+
+    /// Make pushed descriptors visible to the guest and raise the interrupt if configured
+    pub fn notify(&self) -> Result<()> {
+        // Descriptors must be written before the guest can observe the interrupt
+        fence(Ordering::Release);
+        if let Some(subindex) = self.irq {
+            self.ctx.trigger_irq(subindex)?;
+        }
+        Ok(())
+    }
+
+    /// Return to the initial empty state, e.g. on device reset
+    pub fn reset(&mut self) {
+        self.head = 0;
+        self.tail = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geometry(entries: u32) -> RingGeometry {
+        RingGeometry::new::<u64>(0x1000, entries)
+    }
+
+    #[test]
+    fn next_wraps_around() {
+        let ring = geometry(4);
+        assert_eq!(ring.next(0), 1);
+        assert_eq!(ring.next(3), 0);
+
+        let ring = geometry(MAX_RING_ENTRIES);
+        assert_eq!(ring.next(MAX_RING_ENTRIES - 1), 0);
+    }
+
+    #[test]
+    fn empty_and_full() {
+        let ring = geometry(4);
+
+        // Empty: head == tail, nothing pending and all but one slot available
+        assert_eq!(ring.distance(2, 2), 0);
+        assert_eq!(ring.distance(ring.next(2), 2), 3);
+
+        // Full: the slot after the tail is the head
+        let (head, tail) = (1, 0);
+        assert_eq!(ring.next(tail), head);
+        assert_eq!(ring.distance(head, tail), 3);
+        assert_eq!(ring.distance(ring.next(tail), head), 0);
+    }
+
+    #[test]
+    fn distance_wraps_around() {
+        let ring = geometry(8);
+        assert_eq!(ring.distance(6, 2), 4);
+        assert_eq!(ring.distance(2, 6), 4);
+        assert_eq!(ring.distance(7, 0), 1);
+        assert_eq!(ring.distance(0, 7), 7);
+    }
+
+    #[test]
+    fn distance_at_max_entries() {
+        let ring = geometry(MAX_RING_ENTRIES);
+        let last = MAX_RING_ENTRIES - 1;
+        assert_eq!(ring.distance(0, last), last);
+        assert_eq!(ring.distance(last, 0), 1);
+        assert_eq!(ring.distance(ring.next(last), 0), 0);
+        assert_eq!(ring.distance(ring.next(0), 0), last);
+    }
+
+    #[test]
+    fn validate_entries() {
+        assert!(geometry(0).validate::<u64>().is_err());
+        assert!(geometry(1).validate::<u64>().is_err());
+        assert!(geometry(2).validate::<u64>().is_ok());
+        assert!(geometry(MAX_RING_ENTRIES).validate::<u64>().is_ok());
+        assert!(geometry(MAX_RING_ENTRIES + 1).validate::<u64>().is_err());
+    }
+
+    #[test]
+    fn validate_stride_and_overflow() {
+        let mut ring = geometry(4);
+        ring.stride = 4;
+        assert!(ring.validate::<u64>().is_err());
+        ring.stride = 16;
+        assert!(ring.validate::<u64>().is_ok());
+
+        assert!(RingGeometry::new::<u64>(usize::MAX - 8, 2)
+            .validate::<u64>()
+            .is_err());
+        assert!(RingGeometry::new::<u64>(usize::MAX - 16, 2)
+            .validate::<u64>()
+            .is_ok());
+    }
+
+    #[test]
+    fn validate_index() {
+        let ring = geometry(4);
+        assert!(ring.validate_index(3).is_ok());
+        assert!(ring.validate_index(4).is_err());
+        assert!(geometry(MAX_RING_ENTRIES)
+            .validate_index(MAX_RING_ENTRIES)
+            .is_err());
+    }
+}
@@ -0,0 +1,12 @@
+// Functions of libvfio-user used by the wrapper, implemented by the pure-Rust server instead of
+// the C library if feature rust-backend is enabled. The explicit re-exports shadow the bindings
+pub(crate) use libvfio_user_sys::*;
+
+#[cfg(feature = "rust-backend")]
+pub(crate) use crate::server::{
+    dma_sg_size, vfu_addr_to_sgl, vfu_attach_ctx, vfu_create_ctx, vfu_destroy_ctx, vfu_get_poll_fd,
+    vfu_get_private, vfu_irq_trigger, vfu_pci_add_capability, vfu_pci_get_config_space,
+    vfu_pci_init, vfu_pci_set_class, vfu_pci_set_id, vfu_realize_ctx, vfu_run_ctx,
+    vfu_setup_device_dma, vfu_setup_device_nr_irqs, vfu_setup_device_reset_cb, vfu_setup_log,
+    vfu_setup_region, vfu_sg_is_mappable, vfu_sgl_get, vfu_sgl_put, vfu_sgl_read, vfu_sgl_write,
+};
@@ -0,0 +1,253 @@
+//! Drive a [Device] with arbitrary client requests for fuzzing, see the fuzz directory of the
+//! repository for `cargo fuzz` targets.
+//!
+//! Requests are dispatched in-process through the same callbacks libvfio-user calls, without a
+//! client or socket involved. Panics and region accesses reporting another number of bytes than
+//! requested abort the process, which the fuzzer records as a crash. Requires the `rust-backend`,
+//! whose dma table the harness registers dma regions with.
+
+use std::os::fd::AsFd;
+use std::os::raw::c_char;
+use std::rc::Rc;
+
+use arbitrary::Arbitrary;
+
+use crate::ffi::*;
+
+use crate::callbacks::*;
+use crate::memory::SharedMemory;
+use crate::server::{vfu_fuzz_dma_map, vfu_fuzz_dma_unmap, vfu_fuzz_dma_unmap_all};
+use crate::{
+    Device, DeviceConfiguration, DeviceContext, DeviceRegionKind, DeviceResetReason, PanicPolicy,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Single request of a client as seen by the device
+#[derive(Arbitrary, Clone, Debug)]
+pub enum FuzzOperation {
+    /// Access to one of the configured regions, selected by position in the configuration.
+    /// Offset and length are wrapped into the region like libvfio-user bounds-checks them
+    RegionAccess {
+        region: u8,
+        offset: u64,
+        length: u16,
+        write: bool,
+        data: Vec<u8>,
+    },
+    /// Register a dma region of `pages` pages at the page-aligned `iova`
+    DmaMap {
+        iova: u64,
+        pages: u8,
+        read: bool,
+        write: bool,
+    },
+    /// Unregister one of the registered dma regions, selected by position
+    DmaUnmap {
+        region: u8,
+    },
+    Reset {
+        reason: DeviceResetReason,
+    },
+}
+
+type RegionAccessCallback =
+    unsafe extern "C" fn(*mut vfu_ctx_t, *mut c_char, usize, loff_t, bool) -> isize;
+
+struct FuzzRegion {
+    region_type: DeviceRegionKind,
+    size: usize,
+    callback: RegionAccessCallback,
+}
+
+struct FuzzDmaRegion {
+    iova: u64,
+    size: u64,
+}
+
+/// Device produced from a configuration together with the requests it can receive.
+///
+/// Dma regions are backed by shared memory and registered like a client's, so the device is
+/// notified, its [DeviceContext] tracks them and dma performed through the context reaches the
+/// memory. A harness is meant to be set up once and reused for every input, [FuzzHarness::run]
+/// ends with a disconnect which resets the device and drops all dma regions.
+pub struct FuzzHarness<T: Device> {
+    ctx: Rc<DeviceContext>,
+    regions: Vec<FuzzRegion>,
+    dma_regions: Vec<FuzzDmaRegion>,
+    // Dropped last, the context and callbacks refer to it
+    device: Box<T>,
+}
+
+impl<T: Device> FuzzHarness<T> {
+    /// Set up the device, usually once per fuzzing process
+    pub fn new(config: &DeviceConfiguration) -> anyhow::Result<Self> {
+        let (device, ctx) = unsafe { config.setup_all::<T>()? };
+
+        // Panics caught by the callbacks would go unnoticed by the fuzzer otherwise
+        ctx.panic_policy.set(PanicPolicy::Abort);
+
+        // Without always_callback libvfio-user handles parts of the config space itself
+        // and which accesses reach the device cannot be told apart here
+        let regions = config
+            .device_regions
+            .iter()
+            .filter(|region| {
+                !matches!(
+                    region.region_type,
+                    DeviceRegionKind::Config {
+                        always_callback: false
+                    }
+                )
+            })
+            .filter(|region| region.size > 0)
+            .map(|region| FuzzRegion {
+                region_type: region.region_type.clone(),
+                size: region.size,
+                callback: region.region_type.get_region_access_callback_fn::<T>(),
+            })
+            .collect();
+
+        Ok(FuzzHarness {
+            ctx,
+            regions,
+            dma_regions: Vec::new(),
+            device,
+        })
+    }
+
+    pub fn device(&mut self) -> &mut T {
+        &mut self.device
+    }
+
+    pub fn context(&self) -> &DeviceContext {
+        &self.ctx
+    }
+
+    /// Apply all operations in order, then disconnect like a client would
+    pub fn run(&mut self, operations: &[FuzzOperation]) {
+        for operation in operations {
+            self.apply(operation);
+        }
+
+        self.reset(DeviceResetReason::LostConnection);
+    }
+
+    /// Apply a single operation, operations libvfio-user would reject are skipped
+    pub fn apply(&mut self, operation: &FuzzOperation) {
+        match operation {
+            FuzzOperation::RegionAccess {
+                region,
+                offset,
+                length,
+                write,
+                data,
+            } => self.region_access(*region, *offset, *length, *write, data),
+            FuzzOperation::DmaMap {
+                iova,
+                pages,
+                read,
+                write,
+            } => self.dma_map(*iova, *pages, *read, *write),
+            FuzzOperation::DmaUnmap { region } => self.dma_unmap(*region),
+            FuzzOperation::Reset { reason } => self.reset(reason.clone()),
+        }
+    }
+
+    fn region_access(&mut self, region: u8, offset: u64, length: u16, write: bool, data: &[u8]) {
+        if self.regions.is_empty() {
+            return;
+        }
+
+        let region = &self.regions[region as usize % self.regions.len()];
+        let offset = (offset % region.size as u64) as usize;
+        let length = (length as usize).clamp(1, region.size - offset);
+
+        let mut buffer = vec![0u8; length];
+        if write && !data.is_empty() {
+            buffer
+                .iter_mut()
+                .zip(data.iter().cycle())
+                .for_each(|(byte, value)| *byte = *value);
+        }
+
+        let ret = unsafe {
+            (region.callback)(
+                self.ctx.vfu_ctx,
+                buffer.as_mut_ptr() as *mut c_char,
+                length,
+                offset as loff_t,
+                write,
+            )
+        };
+
+        // Errors are negative, libvfio-user fails anything else short of length as well
+        if ret >= 0 && ret != length as isize {
+            panic!(
+                "Region {:?} reported {} bytes processed for a {} of {} bytes at {:#x}",
+                region.region_type,
+                ret,
+                if write { "write" } else { "read" },
+                length,
+                offset
+            );
+        }
+    }
+
+    fn dma_map(&mut self, iova: u64, pages: u8, read: bool, write: bool) {
+        if !self.ctx.dma_enabled || self.dma_regions.len() >= MAX_DMA_REGIONS {
+            return;
+        }
+
+        let iova = iova & !(PAGE_SIZE - 1);
+        let size = pages.max(1) as u64 * PAGE_SIZE;
+
+        let Ok(memory) = SharedMemory::new("vfio-user-fuzz-dma", size as usize) else {
+            return;
+        };
+
+        let mut prot = 0;
+        if read {
+            prot |= libc::PROT_READ;
+        }
+        if write {
+            prot |= libc::PROT_WRITE;
+        }
+
+        // Overlapping or overflowing regions are rejected without notifying the device, the
+        // mapping of the table keeps the memory alive
+        let ret = unsafe { vfu_fuzz_dma_map(self.ctx.vfu_ctx, iova, size, prot, memory.as_fd()) };
+        if ret.is_ok() {
+            self.dma_regions.push(FuzzDmaRegion { iova, size });
+        }
+    }
+
+    fn dma_unmap(&mut self, region: u8) {
+        if self.dma_regions.is_empty() {
+            return;
+        }
+
+        let region = self
+            .dma_regions
+            .remove(region as usize % self.dma_regions.len());
+
+        unsafe { vfu_fuzz_dma_unmap(self.ctx.vfu_ctx, region.iova, region.size) }
+            .expect("Registered dma region is unknown");
+    }
+
+    fn reset(&mut self, reason: DeviceResetReason) {
+        let reset_type = match reason {
+            DeviceResetReason::ClientRequest => vfu_reset_type_VFU_RESET_DEVICE,
+            DeviceResetReason::LostConnection => vfu_reset_type_VFU_RESET_LOST_CONN,
+            DeviceResetReason::PciReset => vfu_reset_type_VFU_RESET_PCI_FLR,
+        };
+
+        unsafe { reset_callback::<T>(self.ctx.vfu_ctx, reset_type) };
+
+        // Losing the client also loses all of its dma regions
+        if let DeviceResetReason::LostConnection = reason {
+            unsafe { vfu_fuzz_dma_unmap_all(self.ctx.vfu_ctx) };
+            self.dma_regions.clear();
+        }
+    }
+}
@@ -1,21 +1,51 @@
 #[macro_use]
 extern crate derive_builder;
 
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
 use std::io::{Error, ErrorKind};
-use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::os::raw::{c_int, c_void};
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
 
 use anyhow::anyhow;
 
-use libvfio_user_sys::*;
+use crate::ffi::*;
+
+use crate::dma::{DmaLease, DmaMapCache, DmaRegionInfo};
+use crate::memory::SharedMemory;
+use crate::socket::SocketFile;
+
+// Re-exported for the Pod bound of typed guest memory access
+pub use bytemuck;
 
 mod callbacks;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "serde")]
+mod config;
 pub mod dma;
+mod ffi;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+pub mod memory;
+// Shared by the client and the rust-backend, which each use only part of it
+#[cfg(any(feature = "client", feature = "rust-backend"))]
+#[cfg_attr(
+    not(all(feature = "client", feature = "rust-backend")),
+    allow(dead_code)
+)]
+mod protocol;
+#[cfg(feature = "rust-backend")]
+mod server;
 mod setup;
+mod socket;
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PciType {
     Pci,
     PciX1,
@@ -35,6 +65,7 @@ impl PciType {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PciConfig {
     pub vendor_id: u16,
     pub device_id: u16,
@@ -47,17 +78,51 @@ pub struct PciConfig {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceRegion {
     pub region_type: DeviceRegionKind,
     pub size: usize,
-    pub file_descriptor: i32,
+    /// File backing the region, kept open by the DeviceContext for as long as it exists
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub file: Option<Arc<File>>,
+    /// Offset of the region inside the backing file
+    #[cfg_attr(feature = "serde", serde(default))]
     pub offset: u64,
+    /// Accesses the region does not permit are rejected before reaching the device
     pub read: bool,
     pub write: bool,
     pub memory: bool,
+    /// Memory backing the region, mappable by the client and accessible via DeviceContext
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub shared_memory: Option<SharedMemory>,
+}
+
+impl DeviceRegion {
+    /// Region backed by a newly created memfd of `size` bytes which the client can map directly.
+    ///
+    /// Accesses the client does not perform through its mapping are still passed to the device's
+    /// region access callback, which can forward them to [SharedMemory::access].
+    pub fn new_shared_memory(
+        region_type: DeviceRegionKind, size: usize, read: bool, write: bool,
+    ) -> anyhow::Result<DeviceRegion> {
+        let name = format!("vfio-user-{:?}", region_type).to_lowercase();
+        let shared_memory = SharedMemory::new(&name, size)?;
+
+        Ok(DeviceRegion {
+            region_type,
+            size,
+            file: None,
+            offset: 0,
+            read,
+            write,
+            memory: true,
+            shared_memory: Some(shared_memory),
+        })
+    }
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DeviceRegionKind {
     Bar0,
     Bar1,
@@ -72,6 +137,7 @@ pub enum DeviceRegionKind {
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum InterruptRequestKind {
     /// Legacy interrupt
     IntX,
@@ -96,22 +162,77 @@ impl InterruptRequestKind {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub enum DeviceResetReason {
     ClientRequest,
     LostConnection,
     PciReset,
 }
 
+/// Socket set up by someone else, e.g. a supervisor or systemd, used instead of the socket_path
+#[derive(Clone, Debug)]
+pub enum PreopenedSocket {
+    /// Bound and listening socket clients connect to
+    Listening(Arc<OwnedFd>),
+    /// Socket already connected to the client, only supported by the rust-backend since
+    /// libvfio-user always accepts connections itself
+    Connected(Arc<OwnedFd>),
+}
+
+/// What happens when the device panics in a callback, after the panic has been logged
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PanicPolicy {
+    /// Fail the request with EIO and keep serving the client
+    Continue,
+    /// Fail the request and disconnect the client, which resets the device
+    Disconnect,
+    /// Abort the process
+    Abort,
+}
+
 #[derive(Builder, Debug)]
 #[builder(name = "DeviceConfigurator", build_fn(validate = "Self::validate"))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "DeviceConfigurator"),
+    builder(derive(serde::Deserialize)),
+    builder_struct_attr(serde(deny_unknown_fields))
+)]
 pub struct DeviceConfiguration {
-    // Path to the socket to be used for communication with the client (e.g. qemu)
+    // Path to the socket to be used for communication with the client (e.g. qemu),
+    // required unless a preopened_socket is given
+    #[builder(default)]
     socket_path: PathBuf,
 
+    // Listening or connected socket to use instead of creating one at socket_path
+    #[builder(setter(strip_option), default)]
+    #[cfg_attr(feature = "serde", serde(skip), builder_field_attr(serde(skip)))]
+    preopened_socket: Option<PreopenedSocket>,
+
     // Remove socket before setup if it already exists
     #[builder(default = "false")]
     overwrite_socket: bool,
 
+    // Permissions and ownership of the socket file, by default as created with the umask
+    #[builder(setter(strip_option), default)]
+    socket_mode: Option<u32>,
+    #[builder(setter(strip_option), default)]
+    socket_owner: Option<u32>,
+    #[builder(setter(strip_option), default)]
+    socket_group: Option<u32>,
+
+    // Remove the socket file again when the DeviceContext is dropped, like libvfio-user it is
+    // left behind by default
+    #[builder(default = "false")]
+    remove_socket: bool,
+
+    // Hold a lock on <socket_path>.lock while the device exists, so other device processes
+    // fail instead of taking over the socket
+    #[builder(default = "false")]
+    lock_socket: bool,
+
     // Run non-blocking, caller must handle waiting/polling for requests itself
     #[builder(default = "false")]
     non_blocking: bool,
@@ -123,14 +244,22 @@ pub struct DeviceConfiguration {
     // Exposed PCI information
     pci_config: PciConfig,
 
-    #[builder(setter(custom))]
+    #[builder(setter(custom), default)]
     device_regions: Vec<DeviceRegion>,
 
-    #[builder(setter(custom))]
+    #[builder(setter(custom), default)]
     interrupt_request_counts: HashMap<InterruptRequestKind, u32>,
 
     #[builder(default = "false")]
     setup_dma: bool,
+
+    // Reuse dma mappings of identical ranges, see DeviceContext::dma_map_cached
+    #[builder(default = "false")]
+    dma_map_cache: bool,
+
+    // How to recover from panics of the device in callbacks
+    #[builder(default = "PanicPolicy::Continue")]
+    panic_policy: PanicPolicy,
 }
 
 impl DeviceConfigurator {
@@ -151,14 +280,41 @@ impl DeviceConfigurator {
 
 impl DeviceConfiguration {
     pub fn produce<T: Device>(&self) -> anyhow::Result<Box<T>> {
-        unsafe { self.setup_all() }
+        unsafe { self.setup_all().map(|(device, _)| device) }
     }
 }
 
+#[derive(Clone, Debug)]
+struct RegionLimits {
+    size: usize,
+    read: bool,
+    write: bool,
+}
+
 #[derive(Debug)]
 pub struct DeviceContext {
     vfu_ctx: *mut vfu_ctx_t,
+    // Type-erased pointer to the boxed device, used by the callbacks
+    device: *mut c_void,
     dma_enabled: bool,
+    // Currently registered dma regions by iova
+    dma_regions: RefCell<BTreeMap<usize, DmaRegionInfo>>,
+    // Ranges and mappings handed out, revoked when their region is unregistered
+    dma_leases: RefCell<Vec<Weak<DmaLease>>>,
+    // Only present if enabled during configuration
+    dma_map_cache: Option<RefCell<DmaMapCache>>,
+    // Shared memory of regions by region index
+    shared_memory: HashMap<c_int, SharedMemory>,
+    // Size and permissions of regions by region index, checked before accesses are dispatched
+    region_limits: HashMap<c_int, RegionLimits>,
+    // Region backing files, dropped only after the vfu context has been destroyed
+    #[allow(dead_code)]
+    region_files: Vec<Arc<File>>,
+    // Applied by the callbacks, a cell so the fuzz harness can make panics fatal
+    panic_policy: Cell<PanicPolicy>,
+    // Socket file and lock created at socket_path, cleaned up after the vfu context is destroyed
+    #[allow(dead_code)]
+    socket_file: Option<SocketFile>,
 }
 
 impl DeviceContext {
@@ -202,6 +358,11 @@ impl DeviceContext {
         }
     }
 
+    /// Shared memory backing the given region, if it was created with shared memory
+    pub fn shared_memory(&self, region: &DeviceRegionKind) -> Option<&SharedMemory> {
+        self.shared_memory.get(&region.to_vfu_region_type())
+    }
+
     pub fn trigger_irq(&self, subindex: u32) -> anyhow::Result<()> {
         unsafe {
             let ret = vfu_irq_trigger(self.vfu_ctx, subindex);
@@ -218,6 +379,11 @@ impl DeviceContext {
 
 impl Drop for DeviceContext {
     fn drop(&mut self) {
+        // Cached mappings must be put back while the vfu context is still alive,
+        // mappings held elsewhere must not touch it afterwards
+        self.clear_dma_map_cache();
+        self.revoke_dma(None);
+
         unsafe {
             vfu_destroy_ctx(self.vfu_ctx);
         }
@@ -244,67 +410,70 @@ pub trait Device {
 
     fn reset(&mut self, reason: DeviceResetReason) -> Result<(), i32>;
 
+    // Accesses to regions whose handler is not implemented fail with EINVAL
     fn region_access_bar0(
         &mut self, offset: usize, data: &mut [u8], write: bool,
     ) -> Result<usize, i32> {
-        unimplemented!()
+        Err(libc::EINVAL)
     }
 
     fn region_access_bar1(
         &mut self, offset: usize, data: &mut [u8], write: bool,
     ) -> Result<usize, i32> {
-        unimplemented!()
+        Err(libc::EINVAL)
     }
 
     fn region_access_bar2(
         &mut self, offset: usize, data: &mut [u8], write: bool,
     ) -> Result<usize, i32> {
-        unimplemented!()
+        Err(libc::EINVAL)
     }
 
     fn region_access_bar3(
         &mut self, offset: usize, data: &mut [u8], write: bool,
     ) -> Result<usize, i32> {
-        unimplemented!()
+        Err(libc::EINVAL)
     }
 
     fn region_access_bar4(
         &mut self, offset: usize, data: &mut [u8], write: bool,
     ) -> Result<usize, i32> {
-        unimplemented!()
+        Err(libc::EINVAL)
     }
 
     fn region_access_bar5(
         &mut self, offset: usize, data: &mut [u8], write: bool,
     ) -> Result<usize, i32> {
-        unimplemented!()
+        Err(libc::EINVAL)
     }
 
     fn region_access_rom(
         &mut self, offset: usize, data: &mut [u8], write: bool,
     ) -> Result<usize, i32> {
-        unimplemented!()
+        Err(libc::EINVAL)
     }
 
     fn region_access_config(
         &mut self, offset: usize, data: &mut [u8], write: bool,
     ) -> Result<usize, i32> {
-        unimplemented!()
+        Err(libc::EINVAL)
     }
 
     fn region_access_vga(
         &mut self, offset: usize, data: &mut [u8], write: bool,
     ) -> Result<usize, i32> {
-        unimplemented!()
+        Err(libc::EINVAL)
     }
 
     fn region_access_migration(
         &mut self, offset: usize, data: &mut [u8], write: bool,
     ) -> Result<usize, i32> {
-        unimplemented!()
+        Err(libc::EINVAL)
     }
 
-    // Optional dma callbacks, regions are also automatically tracked in DeviceContext's dma_regions
-    fn dma_range_added(&mut self, base_address: usize, length: usize) {}
-    fn dma_range_removed(&mut self, base_address: usize) {}
+    // Optional dma callbacks, regions are also automatically tracked in DeviceContext's dma_regions,
+    // a region is already tracked when it is added and still tracked while it is being removed.
+    // Ranges and mappings touching a removed region are revoked once dma_range_removed returns
+    fn dma_range_added(&mut self, info: &DmaRegionInfo) {}
+    fn dma_range_removed(&mut self, info: &DmaRegionInfo) {}
 }
@@ -0,0 +1,174 @@
+use std::ffi::CString;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::Error;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::ptr::null_mut;
+use std::sync::Arc;
+
+use anyhow::{anyhow, ensure, Result};
+
+/// Sealed memfd shared between the device and the client's mappings of a region.
+///
+/// The memory is mapped into this process as well, so the device sees writes done by the client
+/// through its mapping and vice versa. Since the client may access the memory at any time all
+/// accesses from the device side are volatile.
+#[derive(Clone)]
+pub struct SharedMemory {
+    inner: Arc<SharedMemoryInner>,
+}
+
+struct SharedMemoryInner {
+    file: File,
+    ptr: *mut u8,
+    size: usize,
+}
+
+// The mapping is never remapped and only accessed via volatile reads/writes
+unsafe impl Send for SharedMemoryInner {}
+unsafe impl Sync for SharedMemoryInner {}
+
+impl SharedMemory {
+    /// Create a memfd of `size` bytes, seal its size and map it into this process
+    pub fn new(name: &str, size: usize) -> Result<Self> {
+        ensure!(size > 0, "Shared memory must not be empty");
+
+        let name = CString::new(name)?;
+
+        unsafe {
+            let fd = libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING);
+            if fd < 0 {
+                let err = Error::last_os_error();
+                return Err(anyhow!("Failed to create memfd: {}", err));
+            }
+            let file = File::from_raw_fd(fd);
+
+            file.set_len(size as u64)
+                .map_err(|err| anyhow!("Failed to resize memfd: {}", err))?;
+
+            // Client mappings would fault if the file shrinks, so prevent any further resizing
+            let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+            if libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) < 0 {
+                let err = Error::last_os_error();
+                return Err(anyhow!("Failed to seal memfd: {}", err));
+            }
+
+            let ptr = libc::mmap(
+                null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                let err = Error::last_os_error();
+                return Err(anyhow!("Failed to map memfd: {}", err));
+            }
+
+            Ok(SharedMemory {
+                inner: Arc::new(SharedMemoryInner {
+                    file,
+                    ptr: ptr as *mut u8,
+                    size,
+                }),
+            })
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.inner.size
+    }
+
+    /// Pointer to the start of the in-process mapping, valid as long as self is alive
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.inner.ptr
+    }
+
+    pub fn read_volatile(&self, length: usize, offset: usize) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; length];
+        self.read_into_volatile(buffer.as_mut_slice(), offset)?;
+        Ok(buffer)
+    }
+
+    pub fn read_into_volatile(&self, buffer: &mut [u8], offset: usize) -> Result<()> {
+        self.check_bounds(buffer.len(), offset)?;
+
+        unsafe {
+            let ptr = self.inner.ptr.add(offset);
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = ptr.add(i).read_volatile();
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn write_volatile(&self, buffer: &[u8], offset: usize) -> Result<()> {
+        self.check_bounds(buffer.len(), offset)?;
+
+        unsafe {
+            let ptr = self.inner.ptr.add(offset);
+            for (i, byte) in buffer.iter().enumerate() {
+                ptr.add(i).write_volatile(*byte);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serve a region access from the shared memory,
+    /// can be returned directly from the device's region access callback
+    pub fn access(&self, offset: usize, data: &mut [u8], write: bool) -> Result<usize, i32> {
+        let result = if write {
+            self.write_volatile(data, offset)
+        } else {
+            self.read_into_volatile(data, offset)
+        };
+
+        match result {
+            Ok(()) => Ok(data.len()),
+            Err(_) => Err(libc::EINVAL),
+        }
+    }
+
+    fn check_bounds(&self, length: usize, offset: usize) -> Result<()> {
+        ensure!(
+            offset
+                .checked_add(length)
+                .is_some_and(|end| end <= self.inner.size),
+            "Length + offset exceed shared memory size"
+        );
+        Ok(())
+    }
+}
+
+impl Drop for SharedMemoryInner {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+impl Debug for SharedMemory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedMemory")
+            .field("fd", &self.inner.file.as_raw_fd())
+            .field("ptr", &self.inner.ptr)
+            .field("size", &self.inner.size)
+            .finish()
+    }
+}
+
+impl AsFd for SharedMemory {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.file.as_fd()
+    }
+}
+
+impl AsRawFd for SharedMemory {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.file.as_raw_fd()
+    }
+}
@@ -0,0 +1,367 @@
+//! Wire format of the vfio-user protocol, see docs/devel/vfio-user.rst in the QEMU tree.
+
+use std::io::{Error, ErrorKind, Read, Result};
+use std::mem::{size_of, size_of_val};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use bytemuck::{Pod, Zeroable};
+
+pub const MAJOR_VERSION: u16 = 0;
+pub const MINOR_VERSION: u16 = 1;
+
+// Limits announced during version negotiation
+pub const MAX_MSG_FDS: usize = 8;
+pub const MAX_DATA_XFER_SIZE: usize = 1 << 20;
+// Largest message accepted, data transfers plus their headers
+pub const MAX_MSG_SIZE: usize = MAX_DATA_XFER_SIZE + 4096;
+
+pub const CMD_VERSION: u16 = 1;
+pub const CMD_DMA_MAP: u16 = 2;
+pub const CMD_DMA_UNMAP: u16 = 3;
+pub const CMD_DEVICE_GET_INFO: u16 = 4;
+pub const CMD_DEVICE_GET_REGION_INFO: u16 = 5;
+pub const CMD_DEVICE_GET_IRQ_INFO: u16 = 7;
+pub const CMD_DEVICE_SET_IRQS: u16 = 8;
+pub const CMD_REGION_READ: u16 = 9;
+pub const CMD_REGION_WRITE: u16 = 10;
+pub const CMD_DMA_READ: u16 = 11;
+pub const CMD_DMA_WRITE: u16 = 12;
+pub const CMD_DEVICE_RESET: u16 = 13;
+
+pub const FLAG_TYPE_MASK: u32 = 0xf;
+pub const FLAG_TYPE_COMMAND: u32 = 0x0;
+pub const FLAG_TYPE_REPLY: u32 = 0x1;
+pub const FLAG_NO_REPLY: u32 = 0x10;
+pub const FLAG_ERROR: u32 = 0x20;
+
+pub const DMA_FLAG_READ: u32 = 1 << 0;
+pub const DMA_FLAG_WRITE: u32 = 1 << 1;
+pub const DMA_UNMAP_FLAG_GET_DIRTY_BITMAP: u32 = 1 << 0;
+pub const DMA_UNMAP_FLAG_ALL: u32 = 1 << 1;
+
+pub const DEVICE_FLAG_RESET: u32 = 1 << 0;
+pub const DEVICE_FLAG_PCI: u32 = 1 << 1;
+
+pub const REGION_FLAG_READ: u32 = 1 << 0;
+pub const REGION_FLAG_WRITE: u32 = 1 << 1;
+pub const REGION_FLAG_MMAP: u32 = 1 << 2;
+pub const REGION_FLAG_CAPS: u32 = 1 << 3;
+pub const REGION_CAP_SPARSE_MMAP: u16 = 1;
+
+pub const IRQ_INFO_FLAG_EVENTFD: u32 = 1 << 0;
+pub const IRQ_SET_DATA_NONE: u32 = 1 << 0;
+pub const IRQ_SET_DATA_BOOL: u32 = 1 << 1;
+pub const IRQ_SET_DATA_EVENTFD: u32 = 1 << 2;
+pub const IRQ_SET_ACTION_TRIGGER: u32 = 1 << 5;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct Header {
+    pub msg_id: u16,
+    pub command: u16,
+    /// Size of the whole message including this header
+    pub msg_size: u32,
+    pub flags: u32,
+    pub error_no: u32,
+}
+
+impl Header {
+    pub fn is_reply(&self) -> bool {
+        self.flags & FLAG_TYPE_MASK == FLAG_TYPE_REPLY
+    }
+
+    pub fn reply(&self, payload_size: usize) -> Header {
+        Header {
+            msg_id: self.msg_id,
+            command: self.command,
+            msg_size: (size_of::<Header>() + payload_size) as u32,
+            flags: FLAG_TYPE_REPLY,
+            error_no: 0,
+        }
+    }
+
+    pub fn error_reply(&self, error_no: i32) -> Header {
+        Header {
+            flags: FLAG_TYPE_REPLY | FLAG_ERROR,
+            error_no: error_no as u32,
+            ..self.reply(0)
+        }
+    }
+}
+
+/// Followed by the capabilities as NUL-terminated JSON
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct DmaMap {
+    pub argsz: u32,
+    pub flags: u32,
+    /// Offset into the file descriptor passed along, if any
+    pub offset: u64,
+    pub addr: u64,
+    pub size: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct DmaUnmap {
+    pub argsz: u32,
+    pub flags: u32,
+    pub addr: u64,
+    pub size: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct DeviceInfo {
+    pub argsz: u32,
+    pub flags: u32,
+    pub num_regions: u32,
+    pub num_irqs: u32,
+}
+
+/// Followed by capabilities starting at cap_offset if argsz is large enough
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct RegionInfo {
+    pub argsz: u32,
+    pub flags: u32,
+    pub index: u32,
+    pub cap_offset: u32,
+    pub size: u64,
+    /// Offset of the region in the file descriptor passed along, if mappable
+    pub offset: u64,
+}
+
+/// Followed by nr_areas SparseMmapArea
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct SparseMmapCap {
+    pub id: u16,
+    pub version: u16,
+    pub next: u32,
+    pub nr_areas: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct SparseMmapArea {
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct IrqInfo {
+    pub argsz: u32,
+    pub flags: u32,
+    pub index: u32,
+    pub count: u32,
+}
+
+/// Followed by count bools for IRQ_SET_DATA_BOOL, eventfds are passed as file descriptors
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct IrqSet {
+    pub argsz: u32,
+    pub flags: u32,
+    pub index: u32,
+    pub start: u32,
+    pub count: u32,
+}
+
+/// Followed by count bytes of data for writes and read replies
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct RegionAccess {
+    pub offset: u64,
+    pub region: u32,
+    pub count: u32,
+}
+
+/// Followed by count bytes of data for writes and read replies
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct DmaAccess {
+    pub addr: u64,
+    pub count: u64,
+}
+
+pub struct Message {
+    pub header: Header,
+    pub payload: Vec<u8>,
+    pub fds: Vec<OwnedFd>,
+}
+
+impl Message {
+    /// Leading struct of the payload, fails if the payload is too short
+    pub fn read_struct<T: Pod>(&self) -> Result<T> {
+        read_struct(&self.payload)
+    }
+}
+
+pub fn read_struct<T: Pod>(payload: &[u8]) -> Result<T> {
+    payload
+        .get(..size_of::<T>())
+        .map(bytemuck::pod_read_unaligned)
+        .ok_or_else(|| Error::from_raw_os_error(libc::EINVAL))
+}
+
+/// Send a message, the file descriptors accompany its first byte
+pub fn send_message(
+    stream: &UnixStream, header: &Header, payload: &[u8], fds: &[RawFd],
+) -> Result<()> {
+    let header = bytemuck::bytes_of(header);
+    let mut iovecs = [
+        libc::iovec {
+            iov_base: header.as_ptr() as *mut libc::c_void,
+            iov_len: header.len(),
+        },
+        libc::iovec {
+            iov_base: payload.as_ptr() as *mut libc::c_void,
+            iov_len: payload.len(),
+        },
+    ];
+
+    let fds_size = size_of_val(fds) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_size) } as usize];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = iovecs.as_mut_ptr();
+    msg.msg_iovlen = iovecs.len() as _;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_size) as _;
+            let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+            for (i, fd) in fds.iter().enumerate() {
+                data.add(i).write_unaligned(*fd);
+            }
+        }
+    }
+
+    let total = header.len() + payload.len();
+    let mut sent = loop {
+        let ret = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        if ret >= 0 {
+            break ret as usize;
+        }
+        let err = Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    // Short sends only happen for large messages, the file descriptors already went out
+    let message = [header, payload].concat();
+    while sent < total {
+        let ret = unsafe {
+            libc::send(
+                stream.as_raw_fd(),
+                message[sent..].as_ptr() as *const libc::c_void,
+                total - sent,
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if ret < 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        sent += ret as usize;
+    }
+
+    Ok(())
+}
+
+/// Receive the next message, returns None if dont_wait is set and nothing is pending.
+/// A closed connection is reported as NotConnected
+pub fn recv_message(stream: &UnixStream, dont_wait: bool) -> Result<Option<Message>> {
+    let mut header = Header::default();
+    let header_bytes = bytemuck::bytes_of_mut(&mut header);
+    let mut iovec = libc::iovec {
+        iov_base: header_bytes.as_mut_ptr() as *mut libc::c_void,
+        iov_len: header_bytes.len(),
+    };
+
+    let control_size = unsafe { libc::CMSG_SPACE((MAX_MSG_FDS * size_of::<RawFd>()) as u32) };
+    let mut control = vec![0u8; control_size as usize];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iovec;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+
+    let mut flags = libc::MSG_CMSG_CLOEXEC;
+    if dont_wait {
+        flags |= libc::MSG_DONTWAIT;
+    }
+
+    let received = loop {
+        let ret = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, flags) };
+        if ret >= 0 {
+            break ret as usize;
+        }
+        let err = Error::last_os_error();
+        match err.kind() {
+            ErrorKind::Interrupted => continue,
+            ErrorKind::WouldBlock if dont_wait => return Ok(None),
+            _ => return Err(err),
+        }
+    };
+
+    if received == 0 {
+        return Err(Error::from_raw_os_error(libc::ENOTCONN));
+    }
+
+    let fds = unsafe { received_fds(&msg) };
+
+    // The rest of the message follows right away
+    let mut reader = stream;
+    reader.read_exact(&mut bytemuck::bytes_of_mut(&mut header)[received..])?;
+
+    let msg_size = header.msg_size as usize;
+    if msg_size < size_of::<Header>() || msg_size > MAX_MSG_SIZE {
+        return Err(Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let mut payload = vec![0u8; msg_size - size_of::<Header>()];
+    reader.read_exact(&mut payload)?;
+
+    Ok(Some(Message {
+        header,
+        payload,
+        fds,
+    }))
+}
+
+unsafe fn received_fds(msg: &libc::msghdr) -> Vec<OwnedFd> {
+    let mut fds = Vec::new();
+
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+            let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+            let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+            for i in 0..len / size_of::<RawFd>() {
+                fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+            }
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+
+    fds
+}
@@ -0,0 +1,500 @@
+use std::io::{Error, Result};
+use std::mem::size_of;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::os::raw::{c_int, c_void};
+use std::ptr::null_mut;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+use errno::{set_errno, Errno};
+
+use libvfio_user_sys::*;
+
+use crate::protocol::*;
+use crate::server::{errno_error, server, to_c, Reply, Server};
+
+struct DmaRegion {
+    iova: usize,
+    size: usize,
+    prot: c_int,
+    // Mapping of the file descriptor sent by the client, None if it did not send one
+    mapping: Option<iovec>,
+    vaddr: *mut c_void,
+}
+
+impl DmaRegion {
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.iova && addr - self.iova < self.size
+    }
+
+    fn info(&self) -> vfu_dma_info_t {
+        vfu_dma_info_t {
+            iova: iovec {
+                iov_base: self.iova as *mut c_void,
+                iov_len: self.size,
+            },
+            vaddr: self.vaddr,
+            mapping: self.mapping.unwrap_or(iovec {
+                iov_base: null_mut(),
+                iov_len: 0,
+            }),
+            page_size: page_size(),
+            prot: self.prot as u32,
+        }
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        if let Some(mapping) = self.mapping {
+            unsafe { libc::munmap(mapping.iov_base, mapping.iov_len) };
+        }
+    }
+}
+
+pub(super) struct DmaTable {
+    register: vfu_dma_register_cb_t,
+    unregister: vfu_dma_unregister_cb_t,
+    // Indices are referenced by sg entries and stay valid while the region is registered
+    regions: Vec<Option<DmaRegion>>,
+}
+
+impl DmaTable {
+    pub(super) fn new(
+        register: vfu_dma_register_cb_t, unregister: vfu_dma_unregister_cb_t,
+    ) -> DmaTable {
+        DmaTable {
+            register,
+            unregister,
+            regions: Vec::new(),
+        }
+    }
+
+    fn region(&self, index: c_int) -> Result<&DmaRegion> {
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| self.regions.get(index)?.as_ref())
+            .ok_or_else(|| errno_error(libc::EINVAL))
+    }
+
+    fn insert(&mut self, region: DmaRegion) -> usize {
+        match self.regions.iter().position(Option::is_none) {
+            Some(index) => {
+                self.regions[index] = Some(region);
+                index
+            }
+            None => {
+                self.regions.push(Some(region));
+                self.regions.len() - 1
+            }
+        }
+    }
+
+    // Split the range into one entry per region it touches
+    fn to_sgl(&self, dma_addr: usize, len: usize, prot: c_int) -> Result<Vec<private::dma_sg>> {
+        let end = dma_addr
+            .checked_add(len)
+            .ok_or_else(|| errno_error(libc::EINVAL))?;
+        if len == 0 {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        let mut entries = Vec::new();
+        let mut addr = dma_addr;
+        while addr < end {
+            let (index, region) = self
+                .regions
+                .iter()
+                .enumerate()
+                .find_map(|(index, region)| {
+                    let region = region.as_ref()?;
+                    region.contains(addr).then_some((index, region))
+                })
+                .ok_or_else(|| errno_error(libc::ENOENT))?;
+
+            if prot & !region.prot != 0 {
+                return Err(errno_error(libc::EACCES));
+            }
+
+            let offset = addr - region.iova;
+            let length = (region.size - offset).min(end - addr);
+            entries.push(private::dma_sg {
+                dma_addr: region.iova as vfu_dma_addr_t,
+                region: index as c_int,
+                length: length as u64,
+                offset: offset as u64,
+                writeable: region.prot & libc::PROT_WRITE != 0,
+            });
+            addr += length;
+        }
+
+        Ok(entries)
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+// Map the client's memory, returns the whole mapping and the address of the region inside it
+fn map(fd: BorrowedFd, offset: u64, size: usize, prot: c_int) -> Result<(iovec, *mut c_void)> {
+    let aligned_offset = offset & !(page_size() as u64 - 1);
+    let delta = (offset - aligned_offset) as usize;
+    let len = size + delta;
+
+    let base = unsafe {
+        libc::mmap(
+            null_mut(),
+            len,
+            prot,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            aligned_offset as libc::off_t,
+        )
+    };
+    if base == libc::MAP_FAILED {
+        return Err(Error::last_os_error());
+    }
+
+    let mapping = iovec {
+        iov_base: base,
+        iov_len: len,
+    };
+    Ok((mapping, unsafe { (base as *mut u8).add(delta) }
+        as *mut c_void))
+}
+
+impl Server {
+    pub(super) fn dma_map(&self, msg: Message) -> Result<Reply> {
+        let request: DmaMap = msg.read_struct()?;
+
+        // Devices without dma do not need to know about the client's memory
+        if self.dma.borrow().is_none() {
+            return Ok(Reply::new(&[]));
+        }
+
+        let mut prot = 0;
+        if request.flags & DMA_FLAG_READ != 0 {
+            prot |= libc::PROT_READ;
+        }
+        if request.flags & DMA_FLAG_WRITE != 0 {
+            prot |= libc::PROT_WRITE;
+        }
+
+        let fd = msg.fds.first().map(|fd| fd.as_fd());
+        self.dma_add(request.addr, request.size, prot, fd, request.offset)?;
+
+        Ok(Reply::new(&[]))
+    }
+
+    // Register a region and notify the device, the memory is mapped if fd is given
+    fn dma_add(
+        &self, iova: u64, size: u64, prot: c_int, fd: Option<BorrowedFd>, offset: u64,
+    ) -> Result<()> {
+        let (iova, size) = (iova as usize, size as usize);
+        if size == 0 || iova.checked_add(size).is_none() {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        {
+            let dma = self.dma.borrow();
+            let regions = dma.as_ref().unwrap().regions.iter().flatten();
+            if regions
+                .clone()
+                .any(|region| iova < region.iova + region.size && region.iova < iova + size)
+            {
+                return Err(errno_error(libc::EEXIST));
+            }
+            if regions.count() >= MAX_DMA_REGIONS {
+                return Err(errno_error(libc::ENOSPC));
+            }
+        }
+
+        let (mapping, vaddr) = match fd {
+            Some(fd) => {
+                let (mapping, vaddr) = map(fd, offset, size, prot)?;
+                (Some(mapping), vaddr)
+            }
+            None => (None, null_mut()),
+        };
+
+        let region = DmaRegion {
+            iova,
+            size,
+            prot,
+            mapping,
+            vaddr,
+        };
+        let mut info = region.info();
+
+        let register = {
+            let mut dma = self.dma.borrow_mut();
+            let table = dma.as_mut().unwrap();
+            table.insert(region);
+            table.register
+        };
+        if let Some(register) = register {
+            unsafe { register(self.as_vfu_ctx(), &mut info) };
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn dma_unmap(&self, msg: &Message) -> Result<Reply> {
+        let request: DmaUnmap = msg.read_struct()?;
+        if request.flags & DMA_UNMAP_FLAG_GET_DIRTY_BITMAP != 0 {
+            return Err(errno_error(libc::ENOTSUP));
+        }
+
+        let reply = DmaUnmap {
+            argsz: size_of::<DmaUnmap>() as u32,
+            ..request
+        };
+        if self.dma.borrow().is_none() {
+            return Ok(Reply::new(&[bytemuck::bytes_of(&reply)]));
+        }
+
+        if request.flags & DMA_UNMAP_FLAG_ALL != 0 {
+            if request.addr != 0 || request.size != 0 {
+                return Err(errno_error(libc::EINVAL));
+            }
+            self.dma_unmap_all();
+        } else {
+            self.dma_remove(request.addr, request.size)?;
+        }
+
+        Ok(Reply::new(&[bytemuck::bytes_of(&reply)]))
+    }
+
+    // Unregister the region registered with exactly this range
+    fn dma_remove(&self, iova: u64, size: u64) -> Result<()> {
+        let index = self
+            .dma
+            .borrow()
+            .as_ref()
+            .ok_or_else(|| errno_error(libc::EINVAL))?
+            .regions
+            .iter()
+            .position(|region| {
+                region
+                    .as_ref()
+                    .is_some_and(|region| region.iova as u64 == iova && region.size as u64 == size)
+            });
+        self.dma_unmap_index(index.ok_or_else(|| errno_error(libc::ENOENT))?);
+        Ok(())
+    }
+
+    // Unregister all regions, e.g. when the client disconnects
+    pub(super) fn dma_unmap_all(&self) {
+        let count = match self.dma.borrow().as_ref() {
+            Some(table) => table.regions.len(),
+            None => return,
+        };
+        (0..count).for_each(|index| self.dma_unmap_index(index));
+    }
+
+    fn dma_unmap_index(&self, index: usize) {
+        let (mut info, unregister) = {
+            let dma = self.dma.borrow();
+            let Some(table) = dma.as_ref() else {
+                return;
+            };
+            let Some(Some(region)) = table.regions.get(index) else {
+                return;
+            };
+            (region.info(), table.unregister)
+        };
+
+        // The device may still access the region while being notified
+        if let Some(unregister) = unregister {
+            unsafe { unregister(self.as_vfu_ctx(), &mut info) };
+        }
+
+        let region = self.dma.borrow_mut().as_mut().unwrap().regions[index].take();
+        drop(region);
+    }
+
+    // Transfer through the client for regions without a mapping, in chunks it accepts
+    fn dma_transfer(&self, mut addr: u64, data: &mut [u8], write: bool) -> Result<()> {
+        let max_chunk = self
+            .connection()?
+            .max_data_xfer_size
+            .min(MAX_DATA_XFER_SIZE);
+
+        for chunk in data.chunks_mut(max_chunk) {
+            let access = DmaAccess {
+                addr,
+                count: chunk.len() as u64,
+            };
+
+            if write {
+                let payload = [bytemuck::bytes_of(&access), chunk].concat();
+                self.request(CMD_DMA_WRITE, &payload)?;
+            } else {
+                let reply = self.request(CMD_DMA_READ, bytemuck::bytes_of(&access))?;
+                let read = reply
+                    .payload
+                    .get(size_of::<DmaAccess>()..)
+                    .filter(|read| read.len() == chunk.len())
+                    .ok_or_else(|| errno_error(libc::EPROTO))?;
+                chunk.copy_from_slice(read);
+            }
+
+            addr += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    fn sgl_transfer(&self, sg: &private::dma_sg, data: *mut c_void, write: bool) -> Result<()> {
+        let vaddr = {
+            let dma = self.dma.borrow();
+            let region = dma
+                .as_ref()
+                .ok_or_else(|| errno_error(libc::EINVAL))?
+                .region(sg.region)?;
+            if write && !sg.writeable {
+                return Err(errno_error(libc::EACCES));
+            }
+            region.vaddr
+        };
+
+        let data = unsafe { from_raw_parts_mut(data as *mut u8, sg.length as usize) };
+        if vaddr.is_null() {
+            return self.dma_transfer(sg.dma_addr as u64 + sg.offset, data, write);
+        }
+
+        // Mapped regions are accessed directly instead of bothering the client
+        let guest = unsafe { (vaddr as *mut u8).add(sg.offset as usize) };
+        unsafe {
+            if write {
+                guest.copy_from_nonoverlapping(data.as_ptr(), data.len());
+            } else {
+                guest.copy_to_nonoverlapping(data.as_mut_ptr(), data.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Register memory like a DMA_MAP message of a client would, for the fuzz harness
+#[cfg(feature = "fuzzing")]
+pub(crate) unsafe fn vfu_fuzz_dma_map(
+    vfu_ctx: *mut vfu_ctx_t, iova: u64, size: u64, prot: c_int, fd: BorrowedFd,
+) -> Result<()> {
+    let server = server(vfu_ctx);
+    if server.dma.borrow().is_none() {
+        return Err(errno_error(libc::EINVAL));
+    }
+    server.dma_add(iova, size, prot, Some(fd), 0)
+}
+
+#[cfg(feature = "fuzzing")]
+pub(crate) unsafe fn vfu_fuzz_dma_unmap(
+    vfu_ctx: *mut vfu_ctx_t, iova: u64, size: u64,
+) -> Result<()> {
+    server(vfu_ctx).dma_remove(iova, size)
+}
+
+#[cfg(feature = "fuzzing")]
+pub(crate) unsafe fn vfu_fuzz_dma_unmap_all(vfu_ctx: *mut vfu_ctx_t) {
+    server(vfu_ctx).dma_unmap_all()
+}
+
+pub(crate) unsafe fn dma_sg_size() -> usize {
+    size_of::<private::dma_sg>()
+}
+
+pub(crate) unsafe fn vfu_addr_to_sgl(
+    vfu_ctx: *mut vfu_ctx_t, dma_addr: vfu_dma_addr_t, len: usize, sgl: *mut dma_sg_t,
+    max_nr_sgs: usize, prot: c_int,
+) -> c_int {
+    let dma = server(vfu_ctx).dma.borrow();
+    let entries = dma
+        .as_ref()
+        .ok_or_else(|| errno_error(libc::EINVAL))
+        .and_then(|table| table.to_sgl(dma_addr as usize, len, prot));
+
+    match to_c(entries.map(Some), None) {
+        None => -1,
+        // Report the number of entries needed like libvfio-user
+        Some(entries) if entries.len() > max_nr_sgs => -(entries.len() as c_int) - 1,
+        Some(entries) => {
+            let sgl = sgl as *mut private::dma_sg;
+            for (i, entry) in entries.iter().enumerate() {
+                sgl.add(i).write_unaligned(*entry);
+            }
+            entries.len() as c_int
+        }
+    }
+}
+
+pub(crate) unsafe fn vfu_sg_is_mappable(vfu_ctx: *mut vfu_ctx_t, sg: *mut dma_sg_t) -> bool {
+    let sg = (sg as *const private::dma_sg).read_unaligned();
+    let dma = server(vfu_ctx).dma.borrow();
+    dma.as_ref()
+        .and_then(|table| table.region(sg.region).ok())
+        .is_some_and(|region| !region.vaddr.is_null())
+}
+
+pub(crate) unsafe fn vfu_sgl_get(
+    vfu_ctx: *mut vfu_ctx_t, sgl: *mut dma_sg_t, iov: *mut iovec, cnt: usize, _flags: c_int,
+) -> c_int {
+    let dma = server(vfu_ctx).dma.borrow();
+    let Some(table) = dma.as_ref() else {
+        set_errno(Errno(libc::EINVAL));
+        return -1;
+    };
+
+    let sgl = from_raw_parts(sgl as *const private::dma_sg, cnt);
+    let iov = from_raw_parts_mut(iov, cnt);
+    for (sg, iov) in sgl.iter().zip(iov) {
+        let vaddr = match table.region(sg.region) {
+            Ok(region) if !region.vaddr.is_null() => region.vaddr,
+            Ok(_) => {
+                set_errno(Errno(libc::EFAULT));
+                return -1;
+            }
+            Err(err) => return to_c(Err(err), -1),
+        };
+
+        *iov = iovec {
+            iov_base: (vaddr as *mut u8).add(sg.offset as usize) as *mut c_void,
+            iov_len: sg.length as usize,
+        };
+    }
+    0
+}
+
+// Without dirty page tracking there is nothing to release
+pub(crate) unsafe fn vfu_sgl_put(
+    _vfu_ctx: *mut vfu_ctx_t, _sgl: *mut dma_sg_t, _iov: *mut iovec, _cnt: usize,
+) {
+}
+
+pub(crate) unsafe fn vfu_sgl_read(
+    vfu_ctx: *mut vfu_ctx_t, sgl: *mut dma_sg_t, cnt: usize, data: *mut c_void,
+) -> c_int {
+    sgl_transfer(vfu_ctx, sgl, cnt, data, false)
+}
+
+pub(crate) unsafe fn vfu_sgl_write(
+    vfu_ctx: *mut vfu_ctx_t, sgl: *mut dma_sg_t, cnt: usize, data: *mut c_void,
+) -> c_int {
+    sgl_transfer(vfu_ctx, sgl, cnt, data, true)
+}
+
+// Like libvfio-user only single entries are supported
+unsafe fn sgl_transfer(
+    vfu_ctx: *mut vfu_ctx_t, sgl: *mut dma_sg_t, cnt: usize, data: *mut c_void, write: bool,
+) -> c_int {
+    if cnt != 1 {
+        set_errno(Errno(libc::ENOTSUP));
+        return -1;
+    }
+
+    let sg = (sgl as *const private::dma_sg).read_unaligned();
+    to_c(
+        server(vfu_ctx).sgl_transfer(&sg, data, write).map(|_| 0),
+        -1,
+    )
+}
@@ -0,0 +1,788 @@
+//! Pure-Rust implementation of the parts of libvfio-user used by this crate, selected with the
+//! rust-backend feature. The functions mirror the C API so the rest of the crate is unaware of
+//! which backend it runs on, contexts handed out are really pointers to a [Server].
+
+use std::cell::{Cell, RefCell};
+use std::ffi::{CStr, CString, OsStr};
+use std::io::{Error, Result};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::ptr::null_mut;
+use std::rc::Rc;
+use std::slice::from_raw_parts;
+
+use errno::{set_errno, Errno};
+
+use libvfio_user_sys::*;
+
+use crate::protocol::*;
+
+use self::dma::DmaTable;
+use self::pci::ConfigSpace;
+
+pub(crate) use self::dma::{
+    dma_sg_size, vfu_addr_to_sgl, vfu_sg_is_mappable, vfu_sgl_get, vfu_sgl_put, vfu_sgl_read,
+    vfu_sgl_write,
+};
+#[cfg(feature = "fuzzing")]
+pub(crate) use self::dma::{vfu_fuzz_dma_map, vfu_fuzz_dma_unmap, vfu_fuzz_dma_unmap_all};
+pub(crate) use self::pci::{
+    vfu_pci_add_capability, vfu_pci_get_config_space, vfu_pci_init, vfu_pci_set_class,
+    vfu_pci_set_id,
+};
+
+mod dma;
+mod pci;
+#[cfg(all(test, feature = "client"))]
+mod tests;
+
+const NUM_REGIONS: usize = VFU_PCI_DEV_NUM_REGIONS as usize;
+const NUM_IRQ_TYPES: usize = vfu_dev_irq_type_VFU_DEV_REQ_IRQ as usize + 1;
+
+#[derive(Default)]
+struct Region {
+    size: usize,
+    flags: u32,
+    callback: vfu_region_access_cb_t,
+    // Mappable parts as (offset, size), the whole region if empty but a file is given
+    mmap_areas: Vec<(u64, u64)>,
+    file: Option<OwnedFd>,
+    offset: u64,
+}
+
+// Connected client
+struct Connection {
+    stream: UnixStream,
+    // Largest data transfer the client accepts per dma message
+    max_data_xfer_size: usize,
+    next_msg_id: Cell<u16>,
+}
+
+struct Reply {
+    payload: Vec<u8>,
+    fds: Vec<RawFd>,
+}
+
+impl Reply {
+    fn new(parts: &[&[u8]]) -> Reply {
+        Reply {
+            payload: parts.concat(),
+            fds: Vec::new(),
+        }
+    }
+}
+
+pub(crate) struct Server {
+    listener: Option<UnixListener>,
+    // Connection handed over when creating the context, taken by the first attach
+    preconnected: RefCell<Option<UnixStream>>,
+    non_blocking: bool,
+    private: *mut c_void,
+    // Shared with callbacks running while a message is processed
+    connection: RefCell<Option<Rc<Connection>>>,
+    log: Cell<(vfu_log_fn_t, c_int)>,
+    regions: RefCell<Vec<Region>>,
+    irq_counts: Cell<[u32; NUM_IRQ_TYPES]>,
+    // Eventfds of interrupts by type and subindex, as set by the client
+    irq_fds: RefCell<Vec<Vec<Option<OwnedFd>>>>,
+    reset: Cell<vfu_reset_cb_t>,
+    dma: RefCell<Option<DmaTable>>,
+    pci: RefCell<Option<ConfigSpace>>,
+}
+
+fn errno_error(errno: c_int) -> Error {
+    Error::from_raw_os_error(errno)
+}
+
+// Translate results to the C convention of returning -1 and setting errno
+fn to_c<T>(result: Result<T>, error: T) -> T {
+    result.unwrap_or_else(|err| {
+        set_errno(Errno(err.raw_os_error().unwrap_or(libc::EIO)));
+        error
+    })
+}
+
+pub(crate) unsafe fn server<'a>(vfu_ctx: *mut vfu_ctx_t) -> &'a Server {
+    &*(vfu_ctx as *const Server)
+}
+
+impl Server {
+    fn new(
+        listener: Option<UnixListener>, preconnected: Option<UnixStream>, non_blocking: bool,
+        private: *mut c_void,
+    ) -> Result<Server> {
+        if let Some(listener) = &listener {
+            listener.set_nonblocking(non_blocking)?;
+        }
+
+        Ok(Server {
+            listener,
+            preconnected: RefCell::new(preconnected),
+            non_blocking,
+            private,
+            connection: RefCell::new(None),
+            log: Cell::new((None, libc::LOG_ERR)),
+            regions: RefCell::new((0..NUM_REGIONS).map(|_| Region::default()).collect()),
+            irq_counts: Cell::new([0; NUM_IRQ_TYPES]),
+            irq_fds: RefCell::new((0..NUM_IRQ_TYPES).map(|_| Vec::new()).collect()),
+            reset: Cell::new(None),
+            dma: RefCell::new(None),
+            pci: RefCell::new(None),
+        })
+    }
+
+    fn as_vfu_ctx(&self) -> *mut vfu_ctx_t {
+        self as *const Server as *mut vfu_ctx_t
+    }
+
+    fn log(&self, level: c_int, msg: &str) {
+        let (callback, max_level) = self.log.get();
+        if let (Some(callback), true) = (callback, level <= max_level) {
+            let msg = CString::new(msg).unwrap_or_default();
+            unsafe { callback(self.as_vfu_ctx(), level, msg.as_ptr()) };
+        }
+    }
+
+    fn connection(&self) -> Result<Rc<Connection>> {
+        self.connection
+            .borrow()
+            .clone()
+            .ok_or_else(|| errno_error(libc::ENOTCONN))
+    }
+
+    fn attach(&self) -> Result<()> {
+        if self.connection.borrow().is_some() {
+            return Err(errno_error(libc::EISCONN));
+        }
+
+        let preconnected = self.preconnected.borrow_mut().take();
+        let stream = match (preconnected, &self.listener) {
+            (Some(stream), _) => stream,
+            (None, Some(listener)) => listener.accept()?.0,
+            // The preconnected client was the only one there is
+            (None, None) => return Err(errno_error(libc::ENOTCONN)),
+        };
+        stream.set_nonblocking(false)?;
+
+        let max_data_xfer_size = self.negotiate(&stream).map_err(|err| {
+            self.log(
+                libc::LOG_ERR,
+                &format!("Version negotiation failed: {}", err),
+            );
+            err
+        })?;
+
+        *self.connection.borrow_mut() = Some(Rc::new(Connection {
+            stream,
+            max_data_xfer_size,
+            next_msg_id: Cell::new(0),
+        }));
+        self.log(libc::LOG_INFO, "Client attached");
+        Ok(())
+    }
+
+    // Exchange versions and capabilities, returns the client's max_data_xfer_size
+    fn negotiate(&self, stream: &UnixStream) -> Result<usize> {
+        let msg = recv_message(stream, false)?.ok_or_else(|| errno_error(libc::EAGAIN))?;
+        if msg.header.command != CMD_VERSION || msg.header.is_reply() {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        let version: Version = msg.read_struct()?;
+        if version.major != MAJOR_VERSION {
+            send_message(stream, &msg.header.error_reply(libc::ENOTSUP), &[], &[])?;
+            return Err(errno_error(libc::ENOTSUP));
+        }
+
+        // Capabilities are optional, defaults apply to everything left out
+        let json = &msg.payload[size_of::<Version>()..];
+        let json = json.split(|byte| *byte == 0).next().unwrap_or_default();
+        let Some(max_data_xfer_size) = parse_max_data_xfer_size(json) else {
+            send_message(stream, &msg.header.error_reply(libc::EINVAL), &[], &[])?;
+            return Err(errno_error(libc::EINVAL));
+        };
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let reply_capabilities = serde_json::json!({
+            "capabilities": {
+                "max_msg_fds": MAX_MSG_FDS,
+                "max_data_xfer_size": MAX_DATA_XFER_SIZE,
+                "max_dma_maps": MAX_DMA_REGIONS,
+                "pgsizes": page_size,
+            }
+        });
+        let mut json = reply_capabilities.to_string().into_bytes();
+        json.push(0);
+
+        let reply = Version {
+            major: MAJOR_VERSION,
+            minor: version.minor.min(MINOR_VERSION),
+        };
+        let payload = [bytemuck::bytes_of(&reply), &json].concat();
+        send_message(stream, &msg.header.reply(payload.len()), &payload, &[])?;
+
+        Ok(max_data_xfer_size)
+    }
+
+    // Process messages until none are pending if non-blocking or until the client disconnects
+    fn run(&self) -> Result<c_int> {
+        let connection = self.connection()?;
+        let mut processed = 0;
+
+        loop {
+            let result = recv_message(&connection.stream, self.non_blocking)
+                .and_then(|msg| msg.map(|msg| self.handle(&connection, msg)).transpose());
+
+            match result {
+                Ok(Some(())) => processed += 1,
+                Ok(None) => return Ok(processed),
+                Err(err) => {
+                    self.log(libc::LOG_INFO, &format!("Client disconnected: {}", err));
+                    self.detach();
+                    return Err(errno_error(libc::ENOTCONN));
+                }
+            }
+        }
+    }
+
+    // Forget about the client, like libvfio-user this resets the device and drops all dma regions
+    fn detach(&self) {
+        if self.connection.borrow_mut().take().is_none() {
+            return;
+        }
+
+        if let Err(err) = self.call_reset(vfu_reset_type_VFU_RESET_LOST_CONN) {
+            self.log(libc::LOG_WARNING, &format!("Reset failed: {}", err));
+        }
+        self.dma_unmap_all();
+        self.irq_fds.borrow_mut().iter_mut().for_each(Vec::clear);
+    }
+
+    // Returns an error only if the reply could not be sent
+    fn handle(&self, connection: &Connection, msg: Message) -> Result<()> {
+        let header = msg.header;
+        if header.is_reply() {
+            self.log(
+                libc::LOG_WARNING,
+                &format!("Unexpected reply to message {}", header.msg_id),
+            );
+            return Ok(());
+        }
+
+        let result = match header.command {
+            CMD_DMA_MAP => self.dma_map(msg),
+            CMD_DMA_UNMAP => self.dma_unmap(&msg),
+            CMD_DEVICE_GET_INFO => self.device_info(&msg),
+            CMD_DEVICE_GET_REGION_INFO => self.region_info(&msg),
+            CMD_DEVICE_GET_IRQ_INFO => self.irq_info(&msg),
+            CMD_DEVICE_SET_IRQS => self.set_irqs(msg),
+            CMD_REGION_READ | CMD_REGION_WRITE => self.region_access_message(&msg),
+            CMD_DEVICE_RESET => self
+                .call_reset(vfu_reset_type_VFU_RESET_DEVICE)
+                .map(|_| Reply::new(&[])),
+            _ => Err(errno_error(libc::ENOTSUP)),
+        };
+
+        if header.flags & FLAG_NO_REPLY != 0 {
+            return Ok(());
+        }
+
+        match result {
+            Ok(reply) => send_message(
+                &connection.stream,
+                &header.reply(reply.payload.len()),
+                &reply.payload,
+                &reply.fds,
+            ),
+            Err(err) => {
+                self.log(
+                    libc::LOG_DEBUG,
+                    &format!("Command {} failed: {}", header.command, err),
+                );
+                let error_no = err.raw_os_error().unwrap_or(libc::EINVAL);
+                send_message(&connection.stream, &header.error_reply(error_no), &[], &[])
+            }
+        }
+    }
+
+    // Send a command to the client and wait for its reply, used for dma without mappings
+    fn request(&self, command: u16, payload: &[u8]) -> Result<Message> {
+        let connection = self.connection()?;
+
+        let msg_id = connection.next_msg_id.get();
+        connection.next_msg_id.set(msg_id.wrapping_add(1));
+
+        let header = Header {
+            msg_id,
+            command,
+            msg_size: (size_of::<Header>() + payload.len()) as u32,
+            flags: FLAG_TYPE_COMMAND,
+            error_no: 0,
+        };
+        send_message(&connection.stream, &header, payload, &[])?;
+
+        // The client must not send commands of its own until it replied
+        let reply =
+            recv_message(&connection.stream, false)?.ok_or_else(|| errno_error(libc::EAGAIN))?;
+        if !reply.header.is_reply() || reply.header.msg_id != msg_id {
+            self.log(
+                libc::LOG_ERR,
+                &format!("Expected reply to message {}", msg_id),
+            );
+            return Err(errno_error(libc::EPROTO));
+        }
+        if reply.header.flags & FLAG_ERROR != 0 {
+            return Err(errno_error(reply.header.error_no as c_int));
+        }
+
+        Ok(reply)
+    }
+
+    fn call_reset(&self, reset_type: vfu_reset_type_t) -> Result<()> {
+        let Some(callback) = self.reset.get() else {
+            return Ok(());
+        };
+
+        match unsafe { callback(self.as_vfu_ctx(), reset_type) } {
+            0 => Ok(()),
+            -1 => Err(Error::last_os_error()),
+            error => Err(errno_error(error)),
+        }
+    }
+
+    fn device_info(&self, msg: &Message) -> Result<Reply> {
+        let request: DeviceInfo = msg.read_struct()?;
+        if (request.argsz as usize) < size_of::<DeviceInfo>() {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        let info = DeviceInfo {
+            argsz: size_of::<DeviceInfo>() as u32,
+            flags: DEVICE_FLAG_PCI | DEVICE_FLAG_RESET,
+            num_regions: NUM_REGIONS as u32,
+            num_irqs: NUM_IRQ_TYPES as u32,
+        };
+        Ok(Reply::new(&[bytemuck::bytes_of(&info)]))
+    }
+
+    fn region_info(&self, msg: &Message) -> Result<Reply> {
+        let request: RegionInfo = msg.read_struct()?;
+        let index = request.index as usize;
+        if index >= NUM_REGIONS || (request.argsz as usize) < size_of::<RegionInfo>() {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        let regions = self.regions.borrow();
+        let region = &regions[index];
+
+        let mut info = RegionInfo {
+            argsz: size_of::<RegionInfo>() as u32,
+            flags: region.flags & (REGION_FLAG_READ | REGION_FLAG_WRITE),
+            index: request.index,
+            cap_offset: 0,
+            size: region.size as u64,
+            offset: 0,
+        };
+        let mut caps = Vec::new();
+        let mut fds = Vec::new();
+
+        if let Some(file) = &region.file {
+            info.flags |= REGION_FLAG_MMAP;
+            info.offset = region.offset;
+            fds.push(file.as_raw_fd());
+
+            if !region.mmap_areas.is_empty() {
+                let cap = SparseMmapCap {
+                    id: REGION_CAP_SPARSE_MMAP,
+                    version: 1,
+                    next: 0,
+                    nr_areas: region.mmap_areas.len() as u32,
+                    reserved: 0,
+                };
+                caps.extend_from_slice(bytemuck::bytes_of(&cap));
+                for (offset, size) in &region.mmap_areas {
+                    let area = SparseMmapArea {
+                        offset: *offset,
+                        size: *size,
+                    };
+                    caps.extend_from_slice(bytemuck::bytes_of(&area));
+                }
+
+                info.flags |= REGION_FLAG_CAPS;
+                info.cap_offset = size_of::<RegionInfo>() as u32;
+                info.argsz += caps.len() as u32;
+            }
+        }
+
+        // The client retries with a large enough argsz to receive the capabilities
+        if request.argsz < info.argsz {
+            caps.clear();
+        }
+
+        Ok(Reply {
+            payload: [bytemuck::bytes_of(&info), &caps].concat(),
+            fds,
+        })
+    }
+
+    fn irq_info(&self, msg: &Message) -> Result<Reply> {
+        let request: IrqInfo = msg.read_struct()?;
+        let index = request.index as usize;
+        if index >= NUM_IRQ_TYPES || (request.argsz as usize) < size_of::<IrqInfo>() {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        let info = IrqInfo {
+            argsz: size_of::<IrqInfo>() as u32,
+            flags: IRQ_INFO_FLAG_EVENTFD,
+            index: request.index,
+            count: self.irq_counts.get()[index],
+        };
+        Ok(Reply::new(&[bytemuck::bytes_of(&info)]))
+    }
+
+    fn set_irqs(&self, msg: Message) -> Result<Reply> {
+        let request: IrqSet = msg.read_struct()?;
+        let index = request.index as usize;
+        let (start, count) = (request.start as usize, request.count as usize);
+
+        if index >= NUM_IRQ_TYPES
+            || start + count > self.irq_counts.get()[index] as usize
+            || (start > 0 && count == 0)
+        {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        // Masking is left to the device, like libvfio-user does
+        if request.flags & IRQ_SET_ACTION_TRIGGER == 0 {
+            return Ok(Reply::new(&[]));
+        }
+
+        let mut irq_fds = self.irq_fds.borrow_mut();
+        let fds = &mut irq_fds[index];
+        if fds.len() < start + count {
+            fds.resize_with(start + count, || None);
+        }
+
+        match request.flags & (IRQ_SET_DATA_NONE | IRQ_SET_DATA_BOOL | IRQ_SET_DATA_EVENTFD) {
+            // No data and no count disables all interrupts of the type
+            IRQ_SET_DATA_NONE if count == 0 => fds.clear(),
+            IRQ_SET_DATA_NONE => (start..start + count).try_for_each(|i| signal(&fds[i]))?,
+            IRQ_SET_DATA_BOOL => {
+                let data = &msg.payload[size_of::<IrqSet>()..];
+                if data.len() < count {
+                    return Err(errno_error(libc::EINVAL));
+                }
+                for (i, set) in data[..count].iter().enumerate() {
+                    if *set != 0 {
+                        signal(&fds[start + i])?;
+                    }
+                }
+            }
+            IRQ_SET_DATA_EVENTFD if count == 0 => fds.clear(),
+            IRQ_SET_DATA_EVENTFD => {
+                if msg.fds.len() != count {
+                    return Err(errno_error(libc::EINVAL));
+                }
+                for (i, fd) in msg.fds.into_iter().enumerate() {
+                    fds[start + i] = Some(fd);
+                }
+            }
+            _ => return Err(errno_error(libc::EINVAL)),
+        }
+
+        Ok(Reply::new(&[]))
+    }
+
+    fn region_access_message(&self, msg: &Message) -> Result<Reply> {
+        let request: RegionAccess = msg.read_struct()?;
+        let count = request.count as usize;
+        if count > MAX_DATA_XFER_SIZE {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        let request_bytes = bytemuck::bytes_of(&request);
+        if msg.header.command == CMD_REGION_WRITE {
+            let mut data = msg.payload[size_of::<RegionAccess>()..].to_vec();
+            if data.len() != count {
+                return Err(errno_error(libc::EINVAL));
+            }
+            self.region_access(request.region as usize, request.offset, &mut data, true)?;
+            Ok(Reply::new(&[request_bytes]))
+        } else {
+            let mut data = vec![0u8; count];
+            self.region_access(request.region as usize, request.offset, &mut data, false)?;
+            Ok(Reply::new(&[request_bytes, &data]))
+        }
+    }
+
+    fn region_access(&self, index: usize, offset: u64, data: &mut [u8], write: bool) -> Result<()> {
+        let (size, flags, callback) = {
+            let regions = self.regions.borrow();
+            let region = regions
+                .get(index)
+                .ok_or_else(|| errno_error(libc::EINVAL))?;
+            (region.size, region.flags, region.callback)
+        };
+
+        match offset.checked_add(data.len() as u64) {
+            Some(end) if end <= size as u64 => {}
+            _ => return Err(errno_error(libc::EINVAL)),
+        }
+
+        let required = if write {
+            VFU_REGION_FLAG_WRITE
+        } else {
+            VFU_REGION_FLAG_READ
+        };
+        if flags & required == 0 {
+            return Err(errno_error(libc::EPERM));
+        }
+
+        if index == VFU_PCI_DEV_CFG_REGION_IDX as usize {
+            return self.config_access(offset as usize, data, write, flags, callback);
+        }
+
+        let callback = callback.ok_or_else(|| errno_error(libc::EINVAL))?;
+        self.call_region_access(callback, offset, data, write)
+    }
+
+    fn call_region_access(
+        &self,
+        callback: unsafe extern "C" fn(*mut vfu_ctx_t, *mut c_char, usize, loff_t, bool) -> isize,
+        offset: u64, data: &mut [u8], write: bool,
+    ) -> Result<()> {
+        let ret = unsafe {
+            callback(
+                self.as_vfu_ctx(),
+                data.as_mut_ptr() as *mut c_char,
+                data.len(),
+                offset as loff_t,
+                write,
+            )
+        };
+
+        match ret {
+            ..=-1 => Err(Error::last_os_error()),
+            n if n as usize != data.len() => Err(errno_error(libc::EINVAL)),
+            _ => Ok(()),
+        }
+    }
+
+    fn trigger_irq(&self, subindex: u32) -> Result<()> {
+        // Only one type is enabled by the guest at a time, prefer the most capable one
+        let irq_fds = self.irq_fds.borrow();
+        let order = [
+            vfu_dev_irq_type_VFU_DEV_MSIX_IRQ,
+            vfu_dev_irq_type_VFU_DEV_MSI_IRQ,
+            vfu_dev_irq_type_VFU_DEV_INTX_IRQ,
+        ];
+
+        let eventfd = order
+            .iter()
+            .find_map(|irq_type| {
+                let eventfd = irq_fds[*irq_type as usize].get(subindex as usize)?;
+                eventfd.is_some().then_some(eventfd)
+            })
+            .ok_or_else(|| errno_error(libc::ENOENT))?;
+        signal(eventfd)
+    }
+}
+
+// Client's max_data_xfer_size from the capabilities json of the version message,
+// None if the json is malformed, capabilities are not an object or the size is 0
+fn parse_max_data_xfer_size(json: &[u8]) -> Option<usize> {
+    if json.is_empty() {
+        return Some(MAX_DATA_XFER_SIZE);
+    }
+
+    let value = serde_json::from_slice::<serde_json::Value>(json).ok()?;
+    let capabilities = match value.as_object()?.get("capabilities") {
+        Some(capabilities) => capabilities.as_object()?,
+        None => return Some(MAX_DATA_XFER_SIZE),
+    };
+
+    match capabilities.get("max_data_xfer_size") {
+        Some(size) => size
+            .as_u64()
+            .filter(|size| *size > 0)
+            .map(|size| size as usize),
+        None => Some(MAX_DATA_XFER_SIZE),
+    }
+}
+
+// Interrupts without an eventfd are dropped
+fn signal(eventfd: &Option<OwnedFd>) -> Result<()> {
+    let Some(eventfd) = eventfd else {
+        return Ok(());
+    };
+
+    let value = 1u64;
+    let ret = unsafe {
+        libc::write(
+            eventfd.as_raw_fd(),
+            &value as *const u64 as *const c_void,
+            size_of::<u64>(),
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub(crate) unsafe fn vfu_create_ctx(
+    trans: vfu_trans_t, path: *const c_char, flags: c_int, pvt: *mut c_void,
+    dev_type: vfu_dev_type_t,
+) -> *mut vfu_ctx_t {
+    if trans != vfu_trans_t_VFU_TRANS_SOCK || dev_type != vfu_dev_type_t_VFU_DEV_TYPE_PCI {
+        set_errno(Errno(libc::ENOTSUP));
+        return null_mut();
+    }
+
+    let path = PathBuf::from(OsStr::from_bytes(CStr::from_ptr(path).to_bytes()));
+    let non_blocking = flags & LIBVFIO_USER_FLAG_ATTACH_NB as c_int != 0;
+
+    let server = UnixListener::bind(&path)
+        .and_then(|listener| Server::new(Some(listener), None, non_blocking, pvt))
+        .map(|server| Box::into_raw(Box::new(server)));
+    to_c(server, null_mut()) as *mut vfu_ctx_t
+}
+
+/// Like vfu_create_ctx, but serving clients on an existing listening or connected socket
+pub(crate) unsafe fn vfu_create_ctx_from_socket(
+    socket: OwnedFd, listening: bool, flags: c_int, pvt: *mut c_void,
+) -> *mut vfu_ctx_t {
+    let non_blocking = flags & LIBVFIO_USER_FLAG_ATTACH_NB as c_int != 0;
+
+    let server = if listening {
+        Server::new(Some(socket.into()), None, non_blocking, pvt)
+    } else {
+        Server::new(None, Some(socket.into()), non_blocking, pvt)
+    };
+    to_c(
+        server.map(|server| Box::into_raw(Box::new(server))),
+        null_mut(),
+    ) as *mut vfu_ctx_t
+}
+
+// Like libvfio-user, dma regions are unmapped without notifying the device, which is going away
+// as well, and the socket file is left behind
+pub(crate) unsafe fn vfu_destroy_ctx(vfu_ctx: *mut vfu_ctx_t) {
+    if !vfu_ctx.is_null() {
+        drop(Box::from_raw(vfu_ctx as *mut Server));
+    }
+}
+
+pub(crate) unsafe fn vfu_get_private(vfu_ctx: *mut vfu_ctx_t) -> *mut c_void {
+    server(vfu_ctx).private
+}
+
+pub(crate) unsafe fn vfu_get_poll_fd(vfu_ctx: *mut vfu_ctx_t) -> c_int {
+    let server = server(vfu_ctx);
+    if let Some(connection) = server.connection.borrow().as_ref() {
+        return connection.stream.as_raw_fd();
+    }
+
+    match (server.preconnected.borrow().as_ref(), &server.listener) {
+        (Some(stream), _) => stream.as_raw_fd(),
+        (None, Some(listener)) => listener.as_raw_fd(),
+        (None, None) => -1,
+    }
+}
+
+pub(crate) unsafe fn vfu_attach_ctx(vfu_ctx: *mut vfu_ctx_t) -> c_int {
+    to_c(server(vfu_ctx).attach().map(|_| 0), -1)
+}
+
+pub(crate) unsafe fn vfu_run_ctx(vfu_ctx: *mut vfu_ctx_t) -> c_int {
+    to_c(server(vfu_ctx).run(), -1)
+}
+
+pub(crate) unsafe fn vfu_realize_ctx(vfu_ctx: *mut vfu_ctx_t) -> c_int {
+    to_c(server(vfu_ctx).realize().map(|_| 0), -1)
+}
+
+pub(crate) unsafe fn vfu_setup_log(
+    vfu_ctx: *mut vfu_ctx_t, log: vfu_log_fn_t, level: c_int,
+) -> c_int {
+    server(vfu_ctx).log.set((log, level));
+    0
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) unsafe fn vfu_setup_region(
+    vfu_ctx: *mut vfu_ctx_t, region_idx: c_int, size: usize, region_access: vfu_region_access_cb_t,
+    flags: c_int, mmap_areas: *mut iovec, nr_mmap_areas: u32, fd: c_int, offset: u64,
+) -> c_int {
+    let server = server(vfu_ctx);
+    let mut regions = server.regions.borrow_mut();
+
+    let Some(region) = usize::try_from(region_idx)
+        .ok()
+        .and_then(|idx| regions.get_mut(idx))
+    else {
+        set_errno(Errno(libc::EINVAL));
+        return -1;
+    };
+    if nr_mmap_areas > 0 && (mmap_areas.is_null() || fd < 0) {
+        set_errno(Errno(libc::EINVAL));
+        return -1;
+    }
+
+    let areas = match nr_mmap_areas {
+        0 => Vec::new(),
+        n => from_raw_parts(mmap_areas, n as usize)
+            .iter()
+            .map(|area| (area.iov_base as u64, area.iov_len as u64))
+            .collect(),
+    };
+
+    *region = Region {
+        size,
+        flags: flags as u32,
+        callback: region_access,
+        mmap_areas: areas,
+        // Owned from now on like libvfio-user does
+        file: (fd >= 0).then(|| OwnedFd::from_raw_fd(fd)),
+        offset,
+    };
+    0
+}
+
+pub(crate) unsafe fn vfu_setup_device_reset_cb(
+    vfu_ctx: *mut vfu_ctx_t, reset: vfu_reset_cb_t,
+) -> c_int {
+    server(vfu_ctx).reset.set(reset);
+    0
+}
+
+pub(crate) unsafe fn vfu_setup_device_dma(
+    vfu_ctx: *mut vfu_ctx_t, dma_register: vfu_dma_register_cb_t,
+    dma_unregister: vfu_dma_unregister_cb_t,
+) -> c_int {
+    *server(vfu_ctx).dma.borrow_mut() = Some(DmaTable::new(dma_register, dma_unregister));
+    0
+}
+
+pub(crate) unsafe fn vfu_setup_device_nr_irqs(
+    vfu_ctx: *mut vfu_ctx_t, type_: vfu_dev_irq_type, count: u32,
+) -> c_int {
+    let server = server(vfu_ctx);
+    let mut counts = server.irq_counts.get();
+    let Some(irq_count) = counts.get_mut(type_ as usize) else {
+        set_errno(Errno(libc::EINVAL));
+        return -1;
+    };
+    *irq_count = count;
+    server.irq_counts.set(counts);
+    0
+}
+
+pub(crate) unsafe fn vfu_irq_trigger(vfu_ctx: *mut vfu_ctx_t, subindex: u32) -> c_int {
+    to_c(server(vfu_ctx).trigger_irq(subindex).map(|_| 0), -1)
+}
@@ -0,0 +1,432 @@
+use std::io::Result;
+use std::mem::size_of;
+use std::os::raw::{c_int, c_void};
+use std::slice::from_raw_parts;
+
+use errno::{set_errno, Errno};
+
+use libvfio_user_sys::*;
+
+use crate::server::{errno_error, server, to_c, Server};
+
+// Capability flags of vfu_pci_add_capability
+const CAP_FLAG_EXTENDED: c_int = 1 << 0;
+const CAP_FLAG_CALLBACK: c_int = 1 << 1;
+const CAP_FLAG_READONLY: c_int = 1 << 2;
+
+const CFG_SPACE_SIZE: usize = 0x100;
+const CFG_SPACE_EXP_SIZE: usize = 0x1000;
+
+const VENDOR_ID: usize = 0x00;
+const COMMAND: usize = 0x04;
+const STATUS: usize = 0x06;
+const REVISION_ID: usize = 0x08;
+const CLASS_PROG: usize = 0x09;
+const CACHE_LINE_SIZE: usize = 0x0c;
+const LATENCY_TIMER: usize = 0x0d;
+const BAR0: usize = 0x10;
+const SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+const ROM_ADDRESS: usize = 0x30;
+const CAPABILITY_LIST: usize = 0x34;
+const INTERRUPT_LINE: usize = 0x3c;
+const INTERRUPT_PIN: usize = 0x3d;
+const STD_HEADER_SIZE: usize = 0x40;
+
+const STATUS_CAP_LIST: u8 = 0x10;
+// I/O, memory, bus master and parity error response, then SERR and INTx disable
+const COMMAND_WRITABLE: [u8; 2] = [0x47, 0x05];
+const ROM_ENABLE: u32 = 0x1;
+
+const CAP_ID_PM: u8 = 0x01;
+const CAP_ID_MSI: u8 = 0x05;
+const CAP_ID_VNDR: u8 = 0x09;
+const CAP_ID_EXP: u8 = 0x10;
+const CAP_ID_MSIX: u8 = 0x11;
+const EXT_CAP_ID_DSN: u16 = 0x03;
+const EXT_CAP_ID_VNDR: u16 = 0x0b;
+
+const EXP_DEVCTL: usize = 0x08;
+const EXP_DEVCTL_BCR_FLR: u16 = 0x8000;
+
+struct Capability {
+    offset: usize,
+    size: usize,
+    extended: bool,
+    flags: c_int,
+}
+
+impl Capability {
+    fn contains(&self, offset: usize) -> bool {
+        offset >= self.offset && offset < self.offset + self.size
+    }
+
+    // The id and next pointer are maintained by us
+    fn header_size(&self) -> usize {
+        if self.extended {
+            4
+        } else {
+            2
+        }
+    }
+}
+
+pub(super) struct ConfigSpace {
+    // At least as large as vfu_pci_config_space_t, which the wrapper writes to directly
+    data: Box<[u8]>,
+    size: usize,
+    capabilities: Vec<Capability>,
+    // Writable address bits of the BARs and expansion ROM, 0 if unused
+    bar_masks: [u32; 6],
+    rom_mask: u32,
+}
+
+impl ConfigSpace {
+    fn new(pci_type: vfu_pci_type_t, header_type: c_int, revision: c_int) -> Result<ConfigSpace> {
+        let size = match pci_type {
+            x if x == vfu_pci_type_t_VFU_PCI_TYPE_CONVENTIONAL => CFG_SPACE_SIZE,
+            x if x == vfu_pci_type_t_VFU_PCI_TYPE_PCI_X_1 => CFG_SPACE_SIZE,
+            x if x == vfu_pci_type_t_VFU_PCI_TYPE_PCI_X_2 => CFG_SPACE_EXP_SIZE,
+            x if x == vfu_pci_type_t_VFU_PCI_TYPE_EXPRESS => CFG_SPACE_EXP_SIZE,
+            _ => return Err(errno_error(libc::EINVAL)),
+        };
+        // Only type 0 headers, i.e. endpoints, are supported
+        if header_type != 0 {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        let allocation = CFG_SPACE_EXP_SIZE.max(size_of::<vfu_pci_config_space_t>());
+        let mut data = vec![0u8; allocation].into_boxed_slice();
+        data[REVISION_ID] = revision as u8;
+
+        Ok(ConfigSpace {
+            data,
+            size,
+            capabilities: Vec::new(),
+            bar_masks: [0; 6],
+            rom_mask: 0,
+        })
+    }
+
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) {
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn capability_size(&self, extended: bool, data: &[u8]) -> Result<usize> {
+        let size = if extended {
+            match u16::from_le_bytes([data[0], data[1]]) {
+                EXT_CAP_ID_DSN => 12,
+                // Length in the vendor-specific header
+                EXT_CAP_ID_VNDR => (u16::from_le_bytes([data[6], data[7]]) >> 4) as usize,
+                _ => 0,
+            }
+        } else {
+            match data[0] {
+                CAP_ID_PM => 8,
+                CAP_ID_MSI => {
+                    let control = u16::from_le_bytes([data[2], data[3]]);
+                    // 64-bit addresses and per-vector masking extend the capability
+                    10 + if control & 0x80 != 0 { 4 } else { 0 }
+                        + if control & 0x100 != 0 { 10 } else { 0 }
+                }
+                CAP_ID_VNDR => data[2] as usize,
+                CAP_ID_EXP => 60,
+                CAP_ID_MSIX => 12,
+                _ => 0,
+            }
+        };
+
+        match size {
+            0 => Err(errno_error(libc::ENOTSUP)),
+            size => Ok(size),
+        }
+    }
+
+    fn add_capability(&mut self, pos: usize, flags: c_int, data: *const u8) -> Result<usize> {
+        let extended = flags & CAP_FLAG_EXTENDED != 0;
+        if extended && self.size < CFG_SPACE_EXP_SIZE {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        // The size depends on the header, which is at most 8 bytes
+        let size = self.capability_size(extended, unsafe { from_raw_parts(data, 8) })?;
+        let data = unsafe { from_raw_parts(data, size) };
+
+        let (start, end) = if extended {
+            (CFG_SPACE_SIZE, self.size)
+        } else {
+            (STD_HEADER_SIZE, CFG_SPACE_SIZE)
+        };
+        let previous = self
+            .capabilities
+            .iter()
+            .filter(|cap| cap.extended == extended)
+            .max_by_key(|cap| cap.offset)
+            .map(|cap| (cap.offset, cap.size));
+
+        // Place after the previous capability unless a position is requested,
+        // extended capabilities must start at the beginning of the extended space
+        let offset = match pos {
+            0 => previous.map_or(start, |(offset, size)| (offset + size + 3) & !3),
+            pos => pos,
+        };
+        if offset < start
+            || offset + size > end
+            || offset & 3 != 0
+            || (extended && previous.is_none() && offset != start)
+            || self
+                .capabilities
+                .iter()
+                .any(|cap| offset < cap.offset + cap.size && cap.offset < offset + size)
+        {
+            return Err(errno_error(libc::EINVAL));
+        }
+
+        self.data[offset..offset + size].copy_from_slice(data);
+
+        // Link into the list, the next pointer of the new capability terminates it
+        if extended {
+            let header = self.read_u32(offset) & 0x000f_ffff;
+            self.write_u32(offset, header);
+            if let Some((previous, _)) = previous {
+                let header = self.read_u32(previous) & 0x000f_ffff;
+                self.write_u32(previous, header | (offset as u32) << 20);
+            }
+        } else {
+            self.data[offset + 1] = 0;
+            match previous {
+                Some((previous, _)) => self.data[previous + 1] = offset as u8,
+                None => {
+                    self.data[CAPABILITY_LIST] = offset as u8;
+                    self.data[STATUS] |= STATUS_CAP_LIST;
+                }
+            }
+        }
+
+        self.capabilities.push(Capability {
+            offset,
+            size,
+            extended,
+            flags,
+        });
+        Ok(offset)
+    }
+
+    // Derive the BAR size masks and initial values from the regions
+    fn realize(&mut self, regions: &[(usize, u32)], intx: bool) -> Result<()> {
+        for (bar, (size, flags)) in regions.iter().take(6).enumerate() {
+            if *size == 0 {
+                continue;
+            }
+
+            // 64-bit BARs are not supported
+            let io = flags & VFU_REGION_FLAG_MEM == 0;
+            let minimum = if io { 4 } else { 16 };
+            let size = u32::try_from(size.next_power_of_two().max(minimum))
+                .map_err(|_| errno_error(libc::EINVAL))?;
+
+            self.bar_masks[bar] = !(size - 1);
+            self.write_u32(BAR0 + bar * 4, io as u32);
+        }
+
+        let (rom_size, _) = regions[VFU_PCI_DEV_ROM_REGION_IDX as usize];
+        if rom_size > 0 {
+            let size = u32::try_from(rom_size.next_power_of_two().max(2048))
+                .map_err(|_| errno_error(libc::EINVAL))?;
+            self.rom_mask = !(size - 1) | ROM_ENABLE;
+        }
+
+        if intx {
+            self.data[INTERRUPT_PIN] = 1;
+        }
+
+        Ok(())
+    }
+
+    fn capability(&self, offset: usize) -> Option<&Capability> {
+        self.capabilities.iter().find(|cap| cap.contains(offset))
+    }
+
+    /// Whether accesses to the range are emulated here instead of passed to the region callback
+    pub(super) fn is_emulated(&self, offset: usize, len: usize) -> bool {
+        (offset..offset + len).all(|offset| {
+            offset < STD_HEADER_SIZE
+                || self
+                    .capability(offset)
+                    .is_some_and(|cap| cap.flags & CAP_FLAG_CALLBACK == 0)
+        })
+    }
+
+    pub(super) fn read(&self, offset: usize, data: &mut [u8]) {
+        data.copy_from_slice(&self.data[offset..offset + data.len()]);
+    }
+
+    /// Apply a write to the emulated registers, returns whether a function level reset was requested
+    pub(super) fn write(&mut self, offset: usize, data: &[u8]) -> bool {
+        for (i, byte) in data.iter().enumerate() {
+            let offset = offset + i;
+
+            let mask = match offset {
+                COMMAND => COMMAND_WRITABLE[0],
+                0x05 => COMMAND_WRITABLE[1],
+                CACHE_LINE_SIZE | LATENCY_TIMER | INTERRUPT_LINE => 0xff,
+                BAR0..=0x27 | ROM_ADDRESS..=0x33 => 0xff,
+                _ => match self.capability(offset) {
+                    Some(cap) if cap.flags & CAP_FLAG_READONLY != 0 => 0,
+                    Some(cap) if offset < cap.offset + cap.header_size() => 0,
+                    Some(_) => 0xff,
+                    None => 0,
+                },
+            };
+
+            self.data[offset] = (self.data[offset] & !mask) | (byte & mask);
+        }
+
+        // Keep the type bits and only the address bits the size allows
+        for bar in 0..6 {
+            let bar_offset = BAR0 + bar * 4;
+            if bar_offset < offset + data.len() && offset < bar_offset + 4 {
+                let value = self.read_u32(bar_offset);
+                let type_bits = value & !self.bar_masks[bar] & 0x1;
+                self.write_u32(bar_offset, (value & self.bar_masks[bar]) | type_bits);
+            }
+        }
+        if ROM_ADDRESS < offset + data.len() && offset < ROM_ADDRESS + 4 {
+            let value = self.read_u32(ROM_ADDRESS);
+            self.write_u32(ROM_ADDRESS, value & self.rom_mask);
+        }
+
+        // Function level reset is signalled via the PCI Express capability
+        let express = self
+            .capabilities
+            .iter()
+            .find(|cap| !cap.extended && self.data[cap.offset] == CAP_ID_EXP)
+            .map(|cap| cap.offset + EXP_DEVCTL);
+        match express {
+            Some(devctl) if self.read_u16(devctl) & EXP_DEVCTL_BCR_FLR != 0 => {
+                let value = self.read_u16(devctl);
+                self.write_u16(devctl, value & !EXP_DEVCTL_BCR_FLR);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Server {
+    pub(super) fn realize(&self) -> Result<()> {
+        let mut pci = self.pci.borrow_mut();
+        let pci = pci.as_mut().ok_or_else(|| errno_error(libc::EINVAL))?;
+
+        let mut regions = self.regions.borrow_mut();
+        let intx = self.irq_counts.get()[vfu_dev_irq_type_VFU_DEV_INTX_IRQ as usize] > 0;
+        let sizes: Vec<_> = regions
+            .iter()
+            .map(|region| (region.size, region.flags))
+            .collect();
+        pci.realize(&sizes, intx)?;
+
+        // Config space is accessible even without a region set up by the device
+        let config = &mut regions[VFU_PCI_DEV_CFG_REGION_IDX as usize];
+        if config.size == 0 {
+            config.flags = VFU_REGION_FLAG_RW;
+        }
+        config.size = pci.size();
+
+        Ok(())
+    }
+
+    pub(super) fn config_access(
+        &self, offset: usize, data: &mut [u8], write: bool, flags: u32,
+        callback: vfu_region_access_cb_t,
+    ) -> Result<()> {
+        let emulated = {
+            let pci = self.pci.borrow();
+            let pci = pci.as_ref().ok_or_else(|| errno_error(libc::EINVAL))?;
+            flags & VFU_REGION_FLAG_ALWAYS_CB == 0 && pci.is_emulated(offset, data.len())
+        };
+
+        // The device handles everything outside of the header and capabilities
+        if let (false, Some(callback)) = (emulated, callback) {
+            return self.call_region_access(callback, offset as u64, data, write);
+        }
+
+        if !write {
+            self.pci.borrow().as_ref().unwrap().read(offset, data);
+            return Ok(());
+        }
+
+        let reset = self.pci.borrow_mut().as_mut().unwrap().write(offset, data);
+        if reset {
+            self.call_reset(vfu_reset_type_VFU_RESET_PCI_FLR)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) unsafe fn vfu_pci_init(
+    vfu_ctx: *mut vfu_ctx_t, pci_type: vfu_pci_type_t, hdr_type: c_int, revision: c_int,
+) -> c_int {
+    let server = server(vfu_ctx);
+    let pci = ConfigSpace::new(pci_type, hdr_type, revision).map(|pci| {
+        *server.pci.borrow_mut() = Some(pci);
+        0
+    });
+    to_c(pci, -1)
+}
+
+pub(crate) unsafe fn vfu_pci_set_id(
+    vfu_ctx: *mut vfu_ctx_t, vid: u16, did: u16, ssvid: u16, ssid: u16,
+) {
+    if let Some(pci) = server(vfu_ctx).pci.borrow_mut().as_mut() {
+        pci.write_u16(VENDOR_ID, vid);
+        pci.write_u16(VENDOR_ID + 2, did);
+        pci.write_u16(SUBSYSTEM_VENDOR_ID, ssvid);
+        pci.write_u16(SUBSYSTEM_VENDOR_ID + 2, ssid);
+    }
+}
+
+pub(crate) unsafe fn vfu_pci_set_class(vfu_ctx: *mut vfu_ctx_t, base: u8, sub: u8, pi: u8) {
+    if let Some(pci) = server(vfu_ctx).pci.borrow_mut().as_mut() {
+        pci.data[CLASS_PROG..CLASS_PROG + 3].copy_from_slice(&[pi, sub, base]);
+    }
+}
+
+pub(crate) unsafe fn vfu_pci_get_config_space(
+    vfu_ctx: *mut vfu_ctx_t,
+) -> *mut vfu_pci_config_space_t {
+    match server(vfu_ctx).pci.borrow_mut().as_mut() {
+        Some(pci) => pci.data.as_mut_ptr() as *mut vfu_pci_config_space_t,
+        None => {
+            set_errno(Errno(libc::EINVAL));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+pub(crate) unsafe fn vfu_pci_add_capability(
+    vfu_ctx: *mut vfu_ctx_t, pos: usize, flags: c_int, data: *mut c_void,
+) -> isize {
+    let mut pci = server(vfu_ctx).pci.borrow_mut();
+    let offset = pci
+        .as_mut()
+        .ok_or_else(|| errno_error(libc::EINVAL))
+        .and_then(|pci| pci.add_capability(pos, flags, data as *const u8))
+        .map(|offset| offset as isize);
+    to_c(offset, -1)
+}
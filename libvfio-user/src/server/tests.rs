@@ -0,0 +1,421 @@
+//! Drive the rust-backend with the client module over a socketpair
+
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::client::Client;
+use crate::memory::SharedMemory;
+use crate::protocol::*;
+use crate::{
+    Device, DeviceConfigurator, DeviceContext, DeviceRegion, DeviceRegionKind, DeviceResetReason,
+    DmaRegionInfo, InterruptRequestKind, PciConfig, PreopenedSocket,
+};
+
+const BAR0_SIZE: usize = 0x1000;
+const BAR1_SIZE: usize = 0x100;
+// Writing a guest address copies DMA_COPY_SIZE bytes from it to the start of BAR0
+const REG_DMA_COPY: u64 = 0x800;
+const DMA_COPY_SIZE: usize = 16;
+// Writing anything triggers the first interrupt
+const REG_IRQ: u64 = 0x808;
+
+const MSI_INDEX: u32 = 1;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Reset(&'static str),
+    DmaAdded(usize),
+    DmaRemoved(usize),
+}
+
+// Device thread, returns how attaching or running ended and the events the device saw
+type DeviceThread = JoinHandle<(anyhow::Result<()>, Vec<Event>)>;
+
+struct TestDevice {
+    ctx: Rc<DeviceContext>,
+    bar0: Vec<u8>,
+    events: Vec<Event>,
+}
+
+impl TestDevice {
+    fn register_write(&mut self, offset: u64, data: &[u8]) -> Result<(), i32> {
+        match offset {
+            REG_DMA_COPY => {
+                let iova = u64::from_le_bytes(data.try_into().map_err(|_| libc::EINVAL)?);
+                let data = self
+                    .ctx
+                    .dma_range(iova as usize, DMA_COPY_SIZE, 1, true, false)
+                    .and_then(|range| range.read())
+                    .map_err(|_| libc::EFAULT)?;
+                self.bar0[..DMA_COPY_SIZE].copy_from_slice(&data);
+                Ok(())
+            }
+            REG_IRQ => self.ctx.trigger_irq(0).map_err(|_| libc::ENOENT),
+            _ => Err(libc::EINVAL),
+        }
+    }
+}
+
+impl Device for TestDevice {
+    fn new(ctx: Rc<DeviceContext>) -> Self {
+        TestDevice {
+            ctx,
+            bar0: vec![0; BAR0_SIZE],
+            events: Vec::new(),
+        }
+    }
+
+    fn log(&self, _level: i32, _msg: &str) {}
+
+    fn reset(&mut self, reason: DeviceResetReason) -> Result<(), i32> {
+        self.events.push(Event::Reset(match reason {
+            DeviceResetReason::ClientRequest => "client",
+            DeviceResetReason::LostConnection => "lost connection",
+            DeviceResetReason::PciReset => "pci",
+        }));
+        Ok(())
+    }
+
+    fn region_access_bar0(
+        &mut self, offset: usize, data: &mut [u8], write: bool,
+    ) -> Result<usize, i32> {
+        if offset as u64 >= REG_DMA_COPY {
+            if write {
+                self.register_write(offset as u64, data)?;
+            }
+            return Ok(data.len());
+        }
+
+        let memory = &mut self.bar0[offset..offset + data.len()];
+        match write {
+            true => memory.copy_from_slice(data),
+            false => data.copy_from_slice(memory),
+        }
+        Ok(data.len())
+    }
+
+    fn region_access_bar1(
+        &mut self, offset: usize, data: &mut [u8], _write: bool,
+    ) -> Result<usize, i32> {
+        data.iter_mut()
+            .enumerate()
+            .for_each(|(i, byte)| *byte = (offset + i) as u8);
+        Ok(data.len())
+    }
+
+    fn dma_range_added(&mut self, info: &DmaRegionInfo) {
+        self.events.push(Event::DmaAdded(info.iova));
+    }
+
+    fn dma_range_removed(&mut self, info: &DmaRegionInfo) {
+        self.events.push(Event::DmaRemoved(info.iova));
+    }
+}
+
+// Serve one client on the other end of the returned socket
+fn start_device() -> (UnixStream, DeviceThread) {
+    let (client_end, server_end) = UnixStream::pair().unwrap();
+    client_end.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let socket = PreopenedSocket::Connected(Arc::new(OwnedFd::from(server_end)));
+
+    let handle = thread::spawn(move || {
+        let config = DeviceConfigurator::default()
+            .preopened_socket(socket)
+            .pci_config(PciConfig {
+                vendor_id: 0x1234,
+                device_id: 0x5678,
+                subsystem_vendor_id: 0,
+                subsystem_id: 0,
+                class_code_base: 0xff,
+                class_code_subclass: 0,
+                class_code_programming_interface: 0,
+                revision_id: 0,
+            })
+            .add_device_region(region(DeviceRegionKind::Bar0, BAR0_SIZE, true))
+            .add_device_region(region(DeviceRegionKind::Bar1, BAR1_SIZE, false))
+            .using_interrupt_requests(InterruptRequestKind::Msi, 1)
+            .setup_dma(true)
+            .build()
+            .unwrap();
+
+        let mut device = config.produce::<TestDevice>().unwrap();
+        let ctx = device.ctx.clone();
+        // Blocking, only returns once the client is gone
+        let result = ctx.attach().and_then(|_| ctx.run());
+        (result, std::mem::take(&mut device.events))
+    });
+
+    (client_end, handle)
+}
+
+fn region(region_type: DeviceRegionKind, size: usize, write: bool) -> DeviceRegion {
+    DeviceRegion {
+        region_type,
+        size,
+        file: None,
+        offset: 0,
+        read: true,
+        write,
+        memory: true,
+        shared_memory: None,
+    }
+}
+
+fn finish(client: Client, handle: DeviceThread) -> Vec<Event> {
+    drop(client);
+    let (result, events) = handle.join().unwrap();
+    assert!(result.is_err(), "Device kept running after disconnect");
+    events
+}
+
+// Send a command and wait for its reply, without the checks of the client
+fn raw_request(stream: &UnixStream, msg_id: u16, command: u16, payload: &[u8]) -> Header {
+    let header = Header {
+        msg_id,
+        command,
+        msg_size: (size_of::<Header>() + payload.len()) as u32,
+        flags: FLAG_TYPE_COMMAND,
+        error_no: 0,
+    };
+    send_message(stream, &header, payload, &[]).unwrap();
+
+    let reply = recv_message(stream, false).unwrap().unwrap();
+    assert!(reply.header.is_reply());
+    assert_eq!(reply.header.msg_id, msg_id);
+    reply.header
+}
+
+fn raw_version(stream: &UnixStream, json: &str) -> Header {
+    let version = Version {
+        major: MAJOR_VERSION,
+        minor: MINOR_VERSION,
+    };
+    let payload = [bytemuck::bytes_of(&version), json.as_bytes(), &[0]].concat();
+    raw_request(stream, 0, CMD_VERSION, &payload)
+}
+
+fn assert_error(header: Header, error_no: i32) {
+    assert_ne!(header.flags & FLAG_ERROR, 0, "Expected an error reply");
+    assert_eq!(header.error_no, error_no as u32);
+}
+
+#[test]
+fn negotiation() {
+    let (stream, handle) = start_device();
+    let mut client = Client::from_stream(stream).unwrap();
+
+    assert_eq!(client.version(), (MAJOR_VERSION, MINOR_VERSION));
+    assert_eq!(client.max_data_xfer_size(), MAX_DATA_XFER_SIZE);
+    assert!(client.max_dma_maps().is_some_and(|maps| maps > 0));
+
+    let info = client.device_info().unwrap();
+    assert!(info.pci);
+    assert!(info.reset);
+
+    let regions = client.regions().unwrap();
+    assert_eq!(regions[0].size, BAR0_SIZE as u64);
+    assert!(regions[0].read && regions[0].write);
+    assert_eq!(regions[1].size, BAR1_SIZE as u64);
+    assert!(regions[1].read && !regions[1].write);
+
+    assert_eq!(client.irq_info(MSI_INDEX).unwrap().count, 1);
+
+    finish(client, handle);
+}
+
+#[test]
+fn region_read_write() {
+    let (stream, handle) = start_device();
+    let mut client = Client::from_stream(stream).unwrap();
+
+    let data: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+    client.region_write(0, 0x10, &data).unwrap();
+    let mut read = vec![0; data.len()];
+    client.region_read(0, 0x10, &mut read).unwrap();
+    assert_eq!(read, data);
+
+    let mut read = [0; 4];
+    client.region_read(1, 0x20, &mut read).unwrap();
+    assert_eq!(read, [0x20, 0x21, 0x22, 0x23]);
+
+    // Read-only region and accesses beyond the end
+    assert!(client.region_write(1, 0, &[0; 4]).is_err());
+    assert!(client
+        .region_read(0, BAR0_SIZE as u64 - 2, &mut [0; 4])
+        .is_err());
+    assert!(client.region_read(0, u64::MAX, &mut [0; 4]).is_err());
+
+    // Still served after the rejected accesses
+    client.region_read(0, 0x10, &mut read).unwrap();
+    assert_eq!(read, [0, 1, 2, 3]);
+
+    finish(client, handle);
+}
+
+#[test]
+fn dma_map_unmap() {
+    let (stream, handle) = start_device();
+    let mut client = Client::from_stream(stream).unwrap();
+
+    let iova = 0x10_0000;
+    let memory = SharedMemory::new("vfio-user-test-guest", 0x4000).unwrap();
+    memory.write_volatile(b"hello from guest", 0x1000).unwrap();
+    client
+        .dma_map(iova, &memory, 0x1000, 0x2000, true, true)
+        .unwrap();
+
+    // Overlapping regions are rejected
+    assert!(client
+        .dma_map(iova + 0x1000, &memory, 0, 0x1000, true, true)
+        .is_err());
+
+    client
+        .region_write(0, REG_DMA_COPY, &iova.to_le_bytes())
+        .unwrap();
+    let mut copied = [0; DMA_COPY_SIZE];
+    client.region_read(0, 0, &mut copied).unwrap();
+    assert_eq!(&copied, b"hello from guest");
+
+    client.dma_unmap(iova, 0x2000).unwrap();
+    assert!(client.dma_unmap(iova, 0x2000).is_err());
+
+    // The device can no longer reach the region
+    assert!(client
+        .region_write(0, REG_DMA_COPY, &iova.to_le_bytes())
+        .is_err());
+
+    let events = finish(client, handle);
+    assert_eq!(
+        events,
+        [
+            Event::DmaAdded(iova as usize),
+            Event::DmaRemoved(iova as usize),
+            Event::Reset("lost connection"),
+        ]
+    );
+}
+
+#[test]
+fn set_irqs() {
+    let (stream, handle) = start_device();
+    let mut client = Client::from_stream(stream).unwrap();
+
+    // Nothing to signal before the client sets up eventfds
+    assert!(client.region_write(0, REG_IRQ, &[1]).is_err());
+
+    let eventfds = client.enable_irqs(MSI_INDEX).unwrap();
+    assert_eq!(eventfds.len(), 1);
+
+    client.region_write(0, REG_IRQ, &[1]).unwrap();
+    let mut count = 0u64;
+    let ret = unsafe {
+        libc::read(
+            eventfds[0].as_raw_fd(),
+            &mut count as *mut u64 as *mut libc::c_void,
+            size_of::<u64>(),
+        )
+    };
+    assert_eq!(ret, size_of::<u64>() as isize);
+    assert_eq!(count, 1);
+
+    client.disable_irqs(MSI_INDEX).unwrap();
+    assert!(client.region_write(0, REG_IRQ, &[1]).is_err());
+
+    finish(client, handle);
+}
+
+#[test]
+fn reset() {
+    let (stream, handle) = start_device();
+    let mut client = Client::from_stream(stream).unwrap();
+
+    client.reset().unwrap();
+
+    let events = finish(client, handle);
+    assert_eq!(
+        events,
+        [Event::Reset("client"), Event::Reset("lost connection")]
+    );
+}
+
+#[test]
+fn malformed_version() {
+    for json in [
+        "[]",
+        "1",
+        "not json",
+        r#"{"capabilities": 1}"#,
+        r#"{"capabilities": {"max_data_xfer_size": 0}}"#,
+        r#"{"capabilities": {"max_data_xfer_size": "large"}}"#,
+    ] {
+        let (stream, handle) = start_device();
+        assert_error(raw_version(&stream, json), libc::EINVAL);
+
+        drop(stream);
+        let (result, events) = handle.join().unwrap();
+        assert!(result.is_err(), "Attached despite capabilities {}", json);
+        assert!(events.is_empty());
+    }
+}
+
+#[test]
+fn malformed_commands() {
+    let (stream, handle) = start_device();
+    let reply = raw_version(&stream, "");
+    assert_eq!(reply.flags & FLAG_ERROR, 0);
+
+    // Payloads too short for the command's struct
+    assert_error(
+        raw_request(&stream, 1, CMD_REGION_READ, &[0; 4]),
+        libc::EINVAL,
+    );
+    assert_error(raw_request(&stream, 2, CMD_DMA_MAP, &[0; 8]), libc::EINVAL);
+    assert_error(raw_request(&stream, 3, CMD_DMA_UNMAP, &[]), libc::EINVAL);
+    assert_error(
+        raw_request(&stream, 4, CMD_DEVICE_SET_IRQS, &[0; 4]),
+        libc::EINVAL,
+    );
+
+    // Writes announcing more data than they carry
+    let access = RegionAccess {
+        offset: 0,
+        region: 0,
+        count: 8,
+    };
+    let payload = [bytemuck::bytes_of(&access), &[0; 4]].concat();
+    assert_error(
+        raw_request(&stream, 5, CMD_REGION_WRITE, &payload),
+        libc::EINVAL,
+    );
+
+    // Regions the device does not have
+    let access = RegionAccess {
+        offset: 0,
+        region: 100,
+        count: 4,
+    };
+    let reply = raw_request(&stream, 6, CMD_REGION_READ, bytemuck::bytes_of(&access));
+    assert_error(reply, libc::EINVAL);
+
+    assert_error(raw_request(&stream, 7, 0xff, &[]), libc::ENOTSUP);
+
+    // Still served after all of the above
+    let access = RegionAccess {
+        offset: 0,
+        region: 0,
+        count: 4,
+    };
+    let reply = raw_request(&stream, 8, CMD_REGION_READ, bytemuck::bytes_of(&access));
+    assert_eq!(reply.flags & FLAG_ERROR, 0);
+
+    drop(stream);
+    let (result, events) = handle.join().unwrap();
+    assert!(result.is_err());
+    assert_eq!(events, [Event::Reset("lost connection")]);
+}
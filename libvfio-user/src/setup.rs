@@ -1,20 +1,22 @@
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::CString;
-use std::fs;
+use std::fs::File;
 use std::io::Error;
+use std::os::fd::{AsRawFd, RawFd};
 use std::os::raw::{c_int, c_void};
-use std::os::unix::fs::FileTypeExt;
 use std::ptr::null_mut;
 use std::rc::Rc;
 
 use anyhow::{anyhow, Context, Result};
 
-use libvfio_user_sys::*;
+use crate::ffi::*;
 
 use crate::callbacks::*;
+use crate::socket::SocketFile;
 use crate::{
     Device, DeviceConfiguration, DeviceConfigurator, DeviceContext, DeviceRegionKind,
-    InterruptRequestKind,
+    InterruptRequestKind, RegionLimits,
 };
 
 impl DeviceRegionKind {
@@ -35,47 +37,167 @@ impl DeviceRegionKind {
     }
 }
 
+unsafe fn duplicate_fd(fd: RawFd) -> Result<RawFd> {
+    let duplicate = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0);
+    if duplicate < 0 {
+        let err = Error::last_os_error();
+        return Err(anyhow!("Failed to duplicate file descriptor: {}", err));
+    }
+    Ok(duplicate)
+}
+
+fn validate_backing_file(file: &File, offset: u64, size: usize) -> Result<(), String> {
+    let metadata = file
+        .metadata()
+        .map_err(|e| format!("Failed to inspect backing file: {}", e))?;
+
+    // Only regular files (including memfds) have a meaningful size, device files report 0
+    if !metadata.is_file() {
+        return Ok(());
+    }
+
+    let end = offset
+        .checked_add(size as u64)
+        .ok_or("Backing file offset + region size overflows")?;
+
+    if metadata.len() < end {
+        return Err(format!(
+            "Backing file is too small, length={} but offset + size={}",
+            metadata.len(),
+            end
+        ));
+    }
+
+    Ok(())
+}
+
 impl DeviceConfigurator {
     pub(crate) fn validate(&self) -> Result<(), String> {
-        // Check if the regions are valid and unique
+        // Check if the regions are valid and unique,
+        // errors name the offending entry so they can be traced back to a configuration file
         if let Some(regions) = &self.device_regions {
             let mut region_vfu_types = HashSet::new();
-            for region in regions {
+            for (i, region) in regions.iter().enumerate() {
                 let vfu_region_type = region.region_type.to_vfu_region_type();
 
                 if region_vfu_types.contains(&vfu_region_type) {
-                    return Err(format!("Duplicate device region, idx={}", vfu_region_type));
+                    return Err(format!(
+                        "device_regions[{}].region_type: Duplicate device region, idx={}",
+                        i, vfu_region_type
+                    ));
                 }
 
                 region_vfu_types.insert(vfu_region_type);
+
+                if let Some(shared_memory) = &region.shared_memory {
+                    if region.file.is_some() {
+                        return Err(format!(
+                            "device_regions[{}]: Region has both a backing file and shared memory",
+                            i
+                        ));
+                    }
+
+                    if shared_memory.size() < region.size {
+                        return Err(format!(
+                            "device_regions[{}].size: Shared memory is smaller than the region",
+                            i
+                        ));
+                    }
+                }
+
+                if let Some(file) = &region.file {
+                    validate_backing_file(file, region.offset, region.size)
+                        .map_err(|e| format!("device_regions[{}].file: {}", i, e))?;
+                }
+            }
+        }
+
+        let preopened = !matches!(self.preopened_socket, None | Some(None));
+        if self.socket_path.is_none() && !preopened {
+            return Err("socket_path: Required unless a preopened_socket is given".to_string());
+        }
+
+        let socket_file_options = [
+            ("socket_mode", self.socket_mode.flatten().is_some()),
+            ("socket_owner", self.socket_owner.flatten().is_some()),
+            ("socket_group", self.socket_group.flatten().is_some()),
+        ];
+        for (option, set) in socket_file_options {
+            if set && preopened {
+                return Err(format!(
+                    "{}: Not applicable to a preopened_socket, set it up by its creator",
+                    option
+                ));
             }
         }
 
+        if let Some(Some(mode)) = self.socket_mode {
+            if mode & !0o7777 != 0 {
+                return Err(format!("socket_mode: Invalid mode {:#o}", mode));
+            }
+        }
+
+        if self.dma_map_cache == Some(true) && self.setup_dma != Some(true) {
+            return Err("dma_map_cache: Requires setup_dma".to_string());
+        }
+
         Ok(())
     }
 }
 
 impl DeviceConfiguration {
     unsafe fn setup_create<T: Device>(&self) -> Result<(Box<T>, Rc<DeviceContext>)> {
-        if self.overwrite_socket {
-            if let Ok(metadata) = fs::metadata(&self.socket_path) {
-                if metadata.file_type().is_socket() {
-                    fs::remove_file(&self.socket_path)?;
-                }
-            }
-        }
+        // Taken before touching the socket path, so an existing socket is only removed
+        // if no other device holds the lock
+        let socket_file = match &self.preopened_socket {
+            Some(_) => None,
+            None => Some(SocketFile::prepare(self)?),
+        };
+
+        let shared_memory = self
+            .device_regions
+            .iter()
+            .filter_map(|region| {
+                let shared_memory = region.shared_memory.clone()?;
+                Some((region.region_type.to_vfu_region_type(), shared_memory))
+            })
+            .collect();
+
+        let region_limits = self
+            .device_regions
+            .iter()
+            .map(|region| {
+                let limits = RegionLimits {
+                    size: region.size,
+                    read: region.read,
+                    write: region.write,
+                };
+                (region.region_type.to_vfu_region_type(), limits)
+            })
+            .collect();
+
+        let region_files = self
+            .device_regions
+            .iter()
+            .filter_map(|region| region.file.clone())
+            .collect();
+
         let ctx = Rc::new(DeviceContext {
             vfu_ctx: null_mut(),
+            device: null_mut(),
             dma_enabled: self.setup_dma,
+            dma_regions: RefCell::new(BTreeMap::new()),
+            dma_leases: RefCell::new(Vec::new()),
+            dma_map_cache: self.dma_map_cache.then(RefCell::default),
+            shared_memory,
+            region_limits,
+            region_files,
+            panic_policy: Cell::new(self.panic_policy),
+            socket_file,
         });
 
         let mut device = Box::new(T::new(ctx.clone()));
 
-        let socket_path = CString::new(
-            self.socket_path
-                .to_str()
-                .context("Path is not valid unicode")?,
-        )?;
         let flags = if self.non_blocking {
             LIBVFIO_USER_FLAG_ATTACH_NB
         } else {
@@ -87,22 +209,52 @@ impl DeviceConfiguration {
         // which would drop the box and make the pointer invalid, definitely causing segfaults
         let device_pointer = (&mut *device) as *mut T;
 
-        let raw_ctx = vfu_create_ctx(
-            vfu_trans_t_VFU_TRANS_SOCK,
-            socket_path.as_ptr(),
-            flags,
-            device_pointer as *mut c_void,
-            vfu_dev_type_t_VFU_DEV_TYPE_PCI,
-        );
+        // Callbacks receive the context, which does not move since it is reference counted,
+        // and reach the device through it
+        let ctx_pointer = Rc::as_ptr(&ctx) as *mut DeviceContext;
+
+        // Unsafe but easy way to update the ctx without requiring interior mutability,
+        // might make safe in the future
+        (*ctx_pointer).device = device_pointer as *mut c_void;
+
+        let custom_permissions = self.socket_mode.is_some()
+            || self.socket_owner.is_some()
+            || self.socket_group.is_some();
+
+        let raw_ctx = match &self.preopened_socket {
+            Some(socket) => socket.create_ctx(flags, ctx_pointer as *mut c_void)?,
+            // Bound here so the socket is never reachable with the wrong permissions
+            None if custom_permissions => {
+                let socket = SocketFile::bind(self)?;
+                socket.create_ctx(flags, ctx_pointer as *mut c_void)?
+            }
+            None => {
+                let socket_path = CString::new(
+                    self.socket_path
+                        .to_str()
+                        .context("Path is not valid unicode")?,
+                )?;
+
+                vfu_create_ctx(
+                    vfu_trans_t_VFU_TRANS_SOCK,
+                    socket_path.as_ptr(),
+                    flags,
+                    ctx_pointer as *mut c_void,
+                    vfu_dev_type_t_VFU_DEV_TYPE_PCI,
+                )
+            }
+        };
 
         if raw_ctx.is_null() {
             let err = Error::last_os_error();
             return Err(anyhow!("Failed to create VFIO context: {}", err));
         }
 
-        // Unsafe but easy way to update ctx.vfu_ctx without requiring interior mutability,
-        // might make safe in the future
-        (&mut *(Rc::as_ptr(&ctx) as *mut DeviceContext)).vfu_ctx = raw_ctx;
+        (*ctx_pointer).vfu_ctx = raw_ctx;
+
+        if let Some(socket_file) = &ctx.socket_file {
+            socket_file.created(self.remove_socket);
+        }
 
         Ok((device, ctx))
     }
@@ -174,20 +326,48 @@ impl DeviceConfiguration {
 
             let callback = region.region_type.get_region_access_callback_fn::<T>();
 
+            // Shared memory is mappable by the client in its entirety
+            let mut mmap_areas = vec![];
+            let (backing_fd, offset) = match (&region.shared_memory, &region.file) {
+                (Some(shared_memory), _) => {
+                    mmap_areas.push(iovec {
+                        iov_base: null_mut(),
+                        iov_len: region.size,
+                    });
+                    (Some(shared_memory.as_raw_fd()), 0)
+                }
+                (None, Some(file)) => (Some(file.as_raw_fd()), region.offset),
+                (None, None) => (None, 0),
+            };
+
+            // libvfio-user closes region file descriptors when destroying the context,
+            // so hand over a duplicate and keep our own open in the DeviceContext
+            let file_descriptor = match backing_fd {
+                Some(fd) => duplicate_fd(fd)?,
+                None => -1,
+            };
+
             let ret = vfu_setup_region(
                 ctx.vfu_ctx,
                 region_idx,
                 region.size,
                 Some(callback),
                 flags as c_int,
-                null_mut(), // TODO: Allow mappings
-                0,
-                region.file_descriptor,
-                region.offset,
+                if mmap_areas.is_empty() {
+                    null_mut()
+                } else {
+                    mmap_areas.as_mut_ptr()
+                },
+                mmap_areas.len() as u32,
+                file_descriptor,
+                offset,
             );
 
             if ret != 0 {
                 let err = Error::last_os_error();
+                if file_descriptor != -1 {
+                    libc::close(file_descriptor);
+                }
                 return Err(anyhow!("Failed to setup region {:?}: {}", region, err));
             }
         }
@@ -274,7 +454,7 @@ impl DeviceConfiguration {
         Ok(())
     }
 
-    pub(crate) unsafe fn setup_all<T: Device>(&self) -> Result<Box<T>> {
+    pub(crate) unsafe fn setup_all<T: Device>(&self) -> Result<(Box<T>, Rc<DeviceContext>)> {
         let (device, ctx) = self.setup_create()?;
 
         self.setup_log::<T>(&*ctx)?;
@@ -285,6 +465,6 @@ impl DeviceConfiguration {
         self.setup_other_callbacks::<T>(&*ctx)?;
         self.setup_realize::<T>(&*ctx)?;
 
-        Ok(device)
+        Ok((device, ctx))
     }
 }
@@ -0,0 +1,352 @@
+use std::cell::Cell;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::Error;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::raw::{c_int, c_void};
+use std::os::unix::fs::{chown, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+use anyhow::{anyhow, ensure, Context, Result};
+
+use crate::ffi::*;
+
+use crate::{DeviceConfiguration, PreopenedSocket};
+
+// First file descriptor passed by systemd, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub(crate) fn is_listening(fd: RawFd) -> std::io::Result<bool> {
+    let mut listening: c_int = 0;
+    let mut len = size_of::<c_int>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(listening != 0)
+}
+
+impl PreopenedSocket {
+    /// Socket passed by systemd socket activation, if this process was started that way.
+    ///
+    /// Units with Accept=yes pass a connection, which requires the rust-backend, otherwise the
+    /// listening socket is passed. Once the socket is taken the LISTEN_* variables are removed
+    /// from the environment so child processes do not pick it up as well.
+    pub fn from_systemd() -> Result<Option<PreopenedSocket>> {
+        let pid = env::var("LISTEN_PID").ok();
+        let fds = env::var("LISTEN_FDS").ok();
+
+        let (Some(pid), Some(fds)) = (pid, fds) else {
+            return Ok(None);
+        };
+
+        // Inherited from a parent which was activated itself
+        if pid.parse::<u32>().ok() != Some(process::id()) {
+            return Ok(None);
+        }
+
+        let count: u32 = fds
+            .parse()
+            .with_context(|| format!("Invalid LISTEN_FDS {:?}", fds))?;
+        if count == 0 {
+            return Ok(None);
+        }
+        ensure!(
+            count == 1,
+            "Expected a single socket from systemd, got {}",
+            count
+        );
+
+        unsafe {
+            // Passed without FD_CLOEXEC
+            if libc::fcntl(SD_LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+                let err = Error::last_os_error();
+                return Err(anyhow!("Failed to take socket from systemd: {}", err));
+            }
+
+            let fd = Arc::new(OwnedFd::from_raw_fd(SD_LISTEN_FDS_START));
+            for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+                env::remove_var(var);
+            }
+
+            let listening = is_listening(fd.as_raw_fd())
+                .map_err(|err| anyhow!("Socket from systemd is unusable: {}", err))?;
+
+            Ok(Some(if listening {
+                PreopenedSocket::Listening(fd)
+            } else {
+                PreopenedSocket::Connected(fd)
+            }))
+        }
+    }
+
+    fn fd(&self) -> &OwnedFd {
+        match self {
+            PreopenedSocket::Listening(fd) | PreopenedSocket::Connected(fd) => fd,
+        }
+    }
+
+    // Create a vfu context serving clients on this socket, null with errno set like
+    // vfu_create_ctx if the context itself could not be created
+    pub(crate) unsafe fn create_ctx(
+        &self, flags: c_int, private: *mut c_void,
+    ) -> Result<*mut vfu_ctx_t> {
+        let listening = is_listening(self.fd().as_raw_fd())
+            .map_err(|err| anyhow!("Preopened socket is unusable: {}", err))?;
+
+        match self {
+            PreopenedSocket::Listening(_) => {
+                ensure!(listening, "Preopened socket is not listening")
+            }
+            PreopenedSocket::Connected(_) => ensure!(!listening, "Preopened socket is listening"),
+        }
+
+        self.create_backend_ctx(flags, private)
+    }
+
+    #[cfg(feature = "rust-backend")]
+    unsafe fn create_backend_ctx(
+        &self, flags: c_int, private: *mut c_void,
+    ) -> Result<*mut vfu_ctx_t> {
+        let fd = self
+            .fd()
+            .try_clone()
+            .map_err(|err| anyhow!("Failed to duplicate preopened socket: {}", err))?;
+        let listening = matches!(self, PreopenedSocket::Listening(_));
+
+        Ok(crate::server::vfu_create_ctx_from_socket(
+            fd, listening, flags, private,
+        ))
+    }
+
+    #[cfg(not(feature = "rust-backend"))]
+    unsafe fn create_backend_ctx(
+        &self, flags: c_int, private: *mut c_void,
+    ) -> Result<*mut vfu_ctx_t> {
+        use std::ffi::CString;
+        use std::os::raw::c_char;
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+        let PreopenedSocket::Listening(fd) = self else {
+            return Err(anyhow!(
+                "Connected sockets require the rust-backend, libvfio-user accepts clients itself"
+            ));
+        };
+
+        // libvfio-user only listens on sockets it creates itself, so let it create one in a
+        // private directory and swap it for ours before any client can attach
+        let mut template = env::temp_dir()
+            .join("vfio-user-XXXXXX")
+            .into_os_string()
+            .into_vec();
+        template.push(0);
+        if libc::mkdtemp(template.as_mut_ptr() as *mut c_char).is_null() {
+            let err = Error::last_os_error();
+            return Err(anyhow!("Failed to create directory for socket: {}", err));
+        }
+        template.pop();
+        let directory = PathBuf::from(OsString::from_vec(template));
+        let path = directory.join("socket");
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+        let raw_ctx = vfu_create_ctx(
+            vfu_trans_t_VFU_TRANS_SOCK,
+            c_path.as_ptr(),
+            flags,
+            private,
+            vfu_dev_type_t_VFU_DEV_TYPE_PCI,
+        );
+        if raw_ctx.is_null() {
+            // Keep the errno of vfu_create_ctx for the caller
+            let errno = errno::errno();
+            let _ = fs::remove_dir(&directory);
+            errno::set_errno(errno);
+            return Ok(raw_ctx);
+        }
+
+        let listen_fd = vfu_get_poll_fd(raw_ctx);
+        let ret = libc::dup3(fd.as_raw_fd(), listen_fd, libc::O_CLOEXEC);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_dir(&directory);
+
+        if ret < 0 {
+            let err = Error::last_os_error();
+            vfu_destroy_ctx(raw_ctx);
+            return Err(anyhow!("Failed to use preopened socket: {}", err));
+        }
+
+        // The file status flags are shared with the original socket, libvfio-user
+        // only set O_NONBLOCK on its own one
+        let status_flags = libc::fcntl(listen_fd, libc::F_GETFL);
+        let non_blocking = flags & LIBVFIO_USER_FLAG_ATTACH_NB as c_int != 0;
+        let ret = if status_flags < 0 {
+            status_flags
+        } else if non_blocking {
+            libc::fcntl(listen_fd, libc::F_SETFL, status_flags | libc::O_NONBLOCK)
+        } else {
+            libc::fcntl(listen_fd, libc::F_SETFL, status_flags & !libc::O_NONBLOCK)
+        };
+
+        if ret < 0 {
+            let err = Error::last_os_error();
+            vfu_destroy_ctx(raw_ctx);
+            return Err(anyhow!("Failed to configure preopened socket: {}", err));
+        }
+
+        Ok(raw_ctx)
+    }
+}
+
+/// Socket file at the socket_path of a context and the lock guarding it, removed again on drop
+#[derive(Debug)]
+pub(crate) struct SocketFile {
+    path: PathBuf,
+    // Kept open to hold the lock
+    lock: Option<(PathBuf, File)>,
+    // Only set once the socket was created, an existing one may belong to someone else
+    remove: Cell<bool>,
+}
+
+impl SocketFile {
+    /// Lock the socket path and remove an existing socket if configured
+    pub(crate) fn prepare(config: &DeviceConfiguration) -> Result<SocketFile> {
+        let lock = match config.lock_socket {
+            true => Some(lock(&config.socket_path)?),
+            false => None,
+        };
+
+        if config.overwrite_socket {
+            if let Ok(metadata) = fs::metadata(&config.socket_path) {
+                if metadata.file_type().is_socket() {
+                    fs::remove_file(&config.socket_path)?;
+                }
+            }
+        }
+
+        Ok(SocketFile {
+            path: config.socket_path.clone(),
+            lock,
+            remove: Cell::new(false),
+        })
+    }
+
+    pub(crate) fn created(&self, remove: bool) {
+        self.remove.set(remove);
+    }
+
+    /// Create the listening socket with the configured permissions and ownership.
+    ///
+    /// It is bound under a temporary name and only moved to socket_path once set up, so clients
+    /// can never connect while the permissions are still wrong.
+    pub(crate) fn bind(config: &DeviceConfiguration) -> Result<PreopenedSocket> {
+        let path = &config.socket_path;
+
+        // Rename would replace an existing file, bind would fail
+        if fs::symlink_metadata(path).is_ok() {
+            let err = Error::from_raw_os_error(libc::EADDRINUSE);
+            return Err(anyhow!("Failed to bind socket {}: {}", path.display(), err));
+        }
+
+        let file_name = path.file_name().context("Socket path has no file name")?;
+        let mut temporary_name = OsString::from(".");
+        temporary_name.push(file_name);
+        temporary_name.push(format!(".{}.tmp", process::id()));
+        let temporary_path = path.with_file_name(temporary_name);
+
+        let _ = fs::remove_file(&temporary_path);
+        let listener = UnixListener::bind(&temporary_path)
+            .map_err(|err| anyhow!("Failed to bind socket {}: {}", path.display(), err))?;
+
+        let result = set_permissions(&temporary_path, config).and_then(|_| {
+            fs::rename(&temporary_path, path)
+                .map_err(|err| anyhow!("Failed to move socket to {}: {}", path.display(), err))
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary_path);
+        }
+        result?;
+
+        Ok(PreopenedSocket::Listening(Arc::new(listener.into())))
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if self.remove.get() {
+            let _ = fs::remove_file(&self.path);
+        }
+
+        // Removed while still holding the lock, see lock
+        if let Some((lock_path, _)) = &self.lock {
+            let _ = fs::remove_file(lock_path);
+        }
+    }
+}
+
+fn set_permissions(path: &Path, config: &DeviceConfiguration) -> Result<()> {
+    if let Some(mode) = config.socket_mode {
+        fs::set_permissions(path, Permissions::from_mode(mode))
+            .map_err(|err| anyhow!("Failed to set socket mode {:#o}: {}", mode, err))?;
+    }
+
+    if config.socket_owner.is_some() || config.socket_group.is_some() {
+        chown(path, config.socket_owner, config.socket_group)
+            .map_err(|err| anyhow!("Failed to set socket ownership: {}", err))?;
+    }
+
+    Ok(())
+}
+
+// Lock <socket_path>.lock, failing if another process holds it
+fn lock(socket_path: &Path) -> Result<(PathBuf, File)> {
+    let mut lock_path = socket_path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+
+    loop {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|err| anyhow!("Failed to open lock {}: {}", lock_path.display(), err))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+            let err = Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Err(anyhow!(
+                    "Socket {} is in use by another device, locked via {}",
+                    socket_path.display(),
+                    lock_path.display()
+                )),
+                _ => Err(anyhow!("Failed to lock {}: {}", lock_path.display(), err)),
+            };
+        }
+
+        // The previous holder removes the file before releasing the lock, if that happened
+        // after we opened it our lock is on a file nobody else will look at anymore
+        let locked = file.metadata()?;
+        match fs::metadata(&lock_path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                return Ok((lock_path, file));
+            }
+            _ => continue,
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::io::Error;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use anyhow::{anyhow, ensure, Result};
+use clap::{Parser, Subcommand};
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use libvfio_user::{DeviceConfiguration, DeviceConfigurator, PciConfig, PciType, PreopenedSocket};
+
+use crate::models::dma_loopback::DmaLoopbackDevice;
+use crate::models::memory::MemoryDevice;
+use crate::models::scratch::ScratchDevice;
+use crate::models::Model;
+
+mod models;
+
+// Timeout for polling the device, bounds how long a shutdown request may go unnoticed
+const POLL_TIMEOUT_MS: i32 = 100;
+
+// Device::new only receives the context, so models read their options from here
+static OPTIONS: OnceLock<Cli> = OnceLock::new();
+
+/// Run a built-in vfio-user device model, e.g. for smoke-testing QEMU setups
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    /// Path of the socket the client (e.g. qemu) connects to,
+    /// the socket passed by systemd is used instead if socket activated
+    socket_path: PathBuf,
+
+    /// Remove an existing socket at the path before starting
+    #[arg(long)]
+    overwrite_socket: bool,
+
+    /// Permissions of the socket file in octal (e.g. 660), defaults to the umask
+    #[arg(long, value_parser = parse_mode)]
+    socket_mode: Option<u32>,
+
+    /// User id owning the socket file
+    #[arg(long)]
+    socket_owner: Option<u32>,
+
+    /// Group id owning the socket file
+    #[arg(long)]
+    socket_group: Option<u32>,
+
+    /// Wait for a new client after the current one disconnects instead of exiting
+    #[arg(long)]
+    persistent: bool,
+
+    /// Also print debug messages of libvfio-user
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// PCI vendor id, defaults to the one of the model
+    #[arg(long, value_parser = parse_u16)]
+    vendor_id: Option<u16>,
+
+    /// PCI device id, defaults to the one of the model
+    #[arg(long, value_parser = parse_u16)]
+    device_id: Option<u16>,
+
+    #[command(subcommand)]
+    model: ModelOptions,
+}
+
+#[derive(Subcommand, Debug)]
+enum ModelOptions {
+    /// BAR0 backed by shared memory the client can map directly
+    Memory {
+        /// Size of the memory BAR, must be a power of two (e.g. 4096, 0x10000, 64K, 2M)
+        #[arg(long, default_value = "1M", value_parser = parse_size)]
+        size: usize,
+    },
+    /// BAR0 containing 32-bit registers which read back the last value written to them
+    Scratch {
+        /// Number of registers
+        #[arg(long, default_value_t = 64)]
+        registers: usize,
+    },
+    /// DMA engine copying between two guest addresses, raising an interrupt when done
+    DmaLoopback {
+        /// Maximum number of bytes copied by a single transfer
+        #[arg(long, default_value = "1M", value_parser = parse_size)]
+        max_transfer: usize,
+    },
+}
+
+fn parse_u16(value: &str) -> Result<u16> {
+    let value = match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16)?,
+        None => value.parse()?,
+    };
+    Ok(value)
+}
+
+fn parse_mode(value: &str) -> Result<u32> {
+    let value = value.strip_prefix("0o").unwrap_or(value);
+    Ok(u32::from_str_radix(value, 8)?)
+}
+
+fn parse_size(value: &str) -> Result<usize> {
+    let (number, multiplier) = match value.chars().last() {
+        Some('K' | 'k') => (&value[..value.len() - 1], 1 << 10),
+        Some('M' | 'm') => (&value[..value.len() - 1], 1 << 20),
+        Some('G' | 'g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+
+    let number = match number.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16)?,
+        None => number.parse()?,
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Size {} is too large", value))
+}
+
+pub(crate) fn options() -> &'static Cli {
+    OPTIONS
+        .get()
+        .expect("Options are set before the device is created")
+}
+
+fn configure(cli: &Cli) -> Result<DeviceConfiguration> {
+    let mut configurator = DeviceConfigurator::default();
+    configurator
+        .socket_path(cli.socket_path.clone())
+        .overwrite_socket(cli.overwrite_socket)
+        .remove_socket(true)
+        .lock_socket(true)
+        .non_blocking(true)
+        .pci_type(PciType::Pci);
+
+    if let Some(socket) = PreopenedSocket::from_systemd()? {
+        configurator.preopened_socket(socket);
+    }
+    if let Some(mode) = cli.socket_mode {
+        configurator.socket_mode(mode);
+    }
+    if let Some(owner) = cli.socket_owner {
+        configurator.socket_owner(owner);
+    }
+    if let Some(group) = cli.socket_group {
+        configurator.socket_group(group);
+    }
+
+    let (vendor_id, device_id) = match &cli.model {
+        ModelOptions::Memory { size } => {
+            ensure!(size.is_power_of_two(), "Memory size must be a power of two");
+            MemoryDevice::configure(&mut configurator, *size)?;
+            MemoryDevice::PCI_ID
+        }
+        ModelOptions::Scratch { registers } => {
+            ensure!(*registers > 0, "At least one register is required");
+            ScratchDevice::configure(&mut configurator, *registers)?;
+            ScratchDevice::PCI_ID
+        }
+        ModelOptions::DmaLoopback { .. } => {
+            DmaLoopbackDevice::configure(&mut configurator)?;
+            DmaLoopbackDevice::PCI_ID
+        }
+    };
+
+    configurator.pci_config(PciConfig {
+        vendor_id: cli.vendor_id.unwrap_or(vendor_id),
+        device_id: cli.device_id.unwrap_or(device_id),
+        subsystem_vendor_id: 0,
+        subsystem_id: 0,
+        class_code_base: 0xff,
+        class_code_subclass: 0,
+        class_code_programming_interface: 0,
+        revision_id: 0,
+    });
+
+    Ok(configurator.build()?)
+}
+
+/// Wait until the device's file descriptor becomes readable,
+/// returns false if the timeout expired or a signal interrupted the wait
+fn poll_readable(fd: i32) -> Result<bool> {
+    let mut poll_fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    let ret = unsafe { libc::poll(&mut poll_fd, 1, POLL_TIMEOUT_MS) };
+    if ret < 0 {
+        let err = Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::EINTR) => Ok(false),
+            _ => Err(anyhow!("Failed to poll device: {}", err)),
+        };
+    }
+
+    Ok(ret > 0)
+}
+
+fn serve<T: Model>(configuration: &DeviceConfiguration, shutdown: &AtomicBool) -> Result<()> {
+    let device = configuration.produce::<T>()?;
+    let ctx = device.context();
+
+    eprintln!("Listening on {}", options().socket_path.display());
+
+    while !shutdown.load(Ordering::Relaxed) {
+        // Wait for a client
+        match ctx.attach()? {
+            Some(()) => eprintln!("Client attached"),
+            None => {
+                poll_readable(ctx.as_raw_fd())?;
+                continue;
+            }
+        }
+
+        // Serve requests until the client disconnects or we are asked to stop
+        while !shutdown.load(Ordering::Relaxed) {
+            if !poll_readable(ctx.as_raw_fd())? {
+                continue;
+            }
+
+            if let Err(e) = ctx.run() {
+                eprintln!("Client detached: {}", e);
+                break;
+            }
+        }
+
+        if !options().persistent {
+            break;
+        }
+    }
+
+    // Dropping the device drops the last context reference, destroying the vfu context
+    eprintln!("Shutting down");
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cli = OPTIONS.get_or_init(Cli::parse);
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGINT, shutdown.clone())?;
+    signal_hook::flag::register(SIGTERM, shutdown.clone())?;
+
+    let configuration = configure(cli)?;
+
+    match &cli.model {
+        ModelOptions::Memory { .. } => serve::<MemoryDevice>(&configuration, &shutdown),
+        ModelOptions::Scratch { .. } => serve::<ScratchDevice>(&configuration, &shutdown),
+        ModelOptions::DmaLoopback { .. } => serve::<DmaLoopbackDevice>(&configuration, &shutdown),
+    }
+}
@@ -0,0 +1,162 @@
+use std::rc::Rc;
+
+use anyhow::{ensure, Result};
+
+use libvfio_user::{
+    Device, DeviceConfigurator, DeviceContext, DeviceRegion, DeviceRegionKind, DeviceResetReason,
+    InterruptRequestKind,
+};
+
+use crate::models::{access_registers, log, Model};
+use crate::{options, ModelOptions};
+
+// Register layout of BAR0, all registers are 64-bit little endian
+const REG_SOURCE: usize = 0x00;
+const REG_DESTINATION: usize = 0x08;
+const REG_LENGTH: usize = 0x10;
+// Writing any value starts a transfer
+const REG_DOORBELL: usize = 0x18;
+const REG_STATUS: usize = 0x20;
+const REGISTERS_SIZE: usize = 0x28;
+
+const STATUS_DONE: u64 = 1 << 0;
+const STATUS_ERROR: u64 = 1 << 1;
+
+/// DMA engine copying `length` bytes from guest address `source` to `destination`
+/// whenever the doorbell is written, raising interrupt 0 once the transfer finished
+pub(crate) struct DmaLoopbackDevice {
+    ctx: Rc<DeviceContext>,
+    registers: [u8; REGISTERS_SIZE],
+    max_transfer: usize,
+}
+
+impl DmaLoopbackDevice {
+    pub(crate) const PCI_ID: (u16, u16) = (0x1234, 0x0003);
+
+    pub(crate) fn configure(configurator: &mut DeviceConfigurator) -> Result<()> {
+        configurator
+            .add_device_region(DeviceRegion {
+                region_type: DeviceRegionKind::Bar0,
+                size: 0x1000,
+                file: None,
+                offset: 0,
+                read: true,
+                write: true,
+                memory: false,
+                shared_memory: None,
+            })
+            .using_interrupt_requests(InterruptRequestKind::IntX, 1)
+            .using_interrupt_requests(InterruptRequestKind::Msi, 1)
+            .setup_dma(true);
+        Ok(())
+    }
+
+    fn register(&self, offset: usize) -> u64 {
+        let bytes = self.registers[offset..offset + 8].try_into().unwrap();
+        u64::from_le_bytes(bytes)
+    }
+
+    fn set_register(&mut self, offset: usize, value: u64) {
+        self.registers[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn transfer(&mut self) -> Result<()> {
+        let source = self.register(REG_SOURCE) as usize;
+        let destination = self.register(REG_DESTINATION) as usize;
+        let length = self.register(REG_LENGTH) as usize;
+
+        ensure!(length > 0, "Transfer length is zero");
+        ensure!(
+            length <= self.max_transfer,
+            "Transfer length {:#x} exceeds maximum of {:#x}",
+            length,
+            self.max_transfer
+        );
+
+        // Guest memory may be fragmented into as many DMA regions as the client may register
+        let max_regions = self.ctx.max_dma_regions();
+
+        let data = self
+            .ctx
+            .dma_range(source, length, max_regions, true, false)?
+            .read()?;
+
+        self.ctx
+            .dma_range(destination, length, max_regions, false, true)?
+            .write(&data)?;
+
+        Ok(())
+    }
+
+    fn ring_doorbell(&mut self) {
+        let status = match self.transfer() {
+            Ok(()) => STATUS_DONE,
+            Err(e) => {
+                eprintln!("DMA transfer failed: {}", e);
+                STATUS_DONE | STATUS_ERROR
+            }
+        };
+        self.set_register(REG_STATUS, status);
+
+        // The client may not have enabled interrupts, the status register still reflects the result
+        if let Err(e) = self.ctx.trigger_irq(0) {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+impl Device for DmaLoopbackDevice {
+    fn new(ctx: Rc<DeviceContext>) -> Self {
+        let max_transfer = match options().model {
+            ModelOptions::DmaLoopback { max_transfer } => max_transfer,
+            _ => unreachable!("DMA loopback device created for another model"),
+        };
+
+        DmaLoopbackDevice {
+            ctx,
+            registers: [0; REGISTERS_SIZE],
+            max_transfer,
+        }
+    }
+
+    fn log(&self, level: i32, msg: &str) {
+        log(level, msg);
+    }
+
+    fn reset(&mut self, _reason: DeviceResetReason) -> Result<(), i32> {
+        self.registers = [0; REGISTERS_SIZE];
+        Ok(())
+    }
+
+    fn region_access_bar0(
+        &mut self, offset: usize, data: &mut [u8], write: bool,
+    ) -> Result<usize, i32> {
+        // Unused part of the BAR
+        if offset >= REGISTERS_SIZE {
+            if !write {
+                data.fill(0);
+            }
+            return Ok(data.len());
+        }
+
+        // Status is read-only
+        if write && offset + data.len() > REG_STATUS {
+            return Err(libc::EPERM);
+        }
+
+        let processed = access_registers(&mut self.registers, offset, data, write)?;
+
+        if write && offset < REG_DOORBELL + 8 && offset + data.len() > REG_DOORBELL {
+            self.set_register(REG_STATUS, 0);
+            self.ring_doorbell();
+        }
+
+        Ok(processed)
+    }
+}
+
+impl Model for DmaLoopbackDevice {
+    fn context(&self) -> &DeviceContext {
+        &self.ctx
+    }
+}
@@ -0,0 +1,55 @@
+use std::rc::Rc;
+
+use anyhow::Result;
+
+use libvfio_user::{
+    Device, DeviceConfigurator, DeviceContext, DeviceRegion, DeviceRegionKind, DeviceResetReason,
+};
+
+use crate::models::{log, Model};
+
+/// Plain memory BAR, the client normally accesses it through its mapping of the shared memory
+pub(crate) struct MemoryDevice {
+    ctx: Rc<DeviceContext>,
+}
+
+impl MemoryDevice {
+    pub(crate) const PCI_ID: (u16, u16) = (0x1234, 0x0001);
+
+    pub(crate) fn configure(configurator: &mut DeviceConfigurator, size: usize) -> Result<()> {
+        let region = DeviceRegion::new_shared_memory(DeviceRegionKind::Bar0, size, true, true)?;
+        configurator.add_device_region(region);
+        Ok(())
+    }
+}
+
+impl Device for MemoryDevice {
+    fn new(ctx: Rc<DeviceContext>) -> Self {
+        MemoryDevice { ctx }
+    }
+
+    fn log(&self, level: i32, msg: &str) {
+        log(level, msg);
+    }
+
+    fn reset(&mut self, _reason: DeviceResetReason) -> Result<(), i32> {
+        // Memory keeps its contents across resets, like RAM on a real card would
+        Ok(())
+    }
+
+    fn region_access_bar0(
+        &mut self, offset: usize, data: &mut [u8], write: bool,
+    ) -> Result<usize, i32> {
+        let memory = self
+            .ctx
+            .shared_memory(&DeviceRegionKind::Bar0)
+            .ok_or(libc::EIO)?;
+        memory.access(offset, data, write)
+    }
+}
+
+impl Model for MemoryDevice {
+    fn context(&self) -> &DeviceContext {
+        &self.ctx
+    }
+}
@@ -0,0 +1,40 @@
+use libvfio_user::{Device, DeviceContext};
+
+use crate::options;
+
+pub(crate) mod dma_loopback;
+pub(crate) mod memory;
+pub(crate) mod scratch;
+
+const LOG_INFO: i32 = 6;
+
+/// Device model the runner can serve
+pub(crate) trait Model: Device {
+    fn context(&self) -> &DeviceContext;
+}
+
+/// Print libvfio-user log messages, debug messages only if requested
+pub(crate) fn log(level: i32, msg: &str) {
+    if level <= LOG_INFO || options().verbose {
+        eprintln!("[libvfio-user:{}] {}", level, msg);
+    }
+}
+
+/// Copy between a register file and an access buffer,
+/// returns the number of bytes processed or EINVAL if the access is out of bounds
+pub(crate) fn access_registers(
+    registers: &mut [u8], offset: usize, data: &mut [u8], write: bool,
+) -> Result<usize, i32> {
+    let end = offset
+        .checked_add(data.len())
+        .filter(|end| *end <= registers.len())
+        .ok_or(libc::EINVAL)?;
+
+    if write {
+        registers[offset..end].copy_from_slice(data);
+    } else {
+        data.copy_from_slice(&registers[offset..end]);
+    }
+
+    Ok(data.len())
+}
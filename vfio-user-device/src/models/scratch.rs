@@ -0,0 +1,80 @@
+use std::rc::Rc;
+
+use anyhow::Result;
+
+use libvfio_user::{
+    Device, DeviceConfigurator, DeviceContext, DeviceRegion, DeviceRegionKind, DeviceResetReason,
+};
+
+use crate::models::{access_registers, log, Model};
+use crate::{options, ModelOptions};
+
+const REGISTER_SIZE: usize = 4;
+
+/// BAR0 of 32-bit registers which read back whatever was last written to them
+pub(crate) struct ScratchDevice {
+    ctx: Rc<DeviceContext>,
+    registers: Vec<u8>,
+}
+
+impl ScratchDevice {
+    pub(crate) const PCI_ID: (u16, u16) = (0x1234, 0x0002);
+
+    pub(crate) fn configure(configurator: &mut DeviceConfigurator, registers: usize) -> Result<()> {
+        configurator.add_device_region(DeviceRegion {
+            region_type: DeviceRegionKind::Bar0,
+            // BAR sizes must be a power of two
+            size: (registers * REGISTER_SIZE).next_power_of_two(),
+            file: None,
+            offset: 0,
+            read: true,
+            write: true,
+            memory: false,
+            shared_memory: None,
+        });
+        Ok(())
+    }
+}
+
+impl Device for ScratchDevice {
+    fn new(ctx: Rc<DeviceContext>) -> Self {
+        let registers = match options().model {
+            ModelOptions::Scratch { registers } => registers,
+            _ => unreachable!("Scratch device created for another model"),
+        };
+
+        ScratchDevice {
+            ctx,
+            registers: vec![0; registers * REGISTER_SIZE],
+        }
+    }
+
+    fn log(&self, level: i32, msg: &str) {
+        log(level, msg);
+    }
+
+    fn reset(&mut self, _reason: DeviceResetReason) -> Result<(), i32> {
+        self.registers.fill(0);
+        Ok(())
+    }
+
+    fn region_access_bar0(
+        &mut self, offset: usize, data: &mut [u8], write: bool,
+    ) -> Result<usize, i32> {
+        // The BAR may be larger than the register file, ignore writes and read zero beyond it
+        if offset >= self.registers.len() {
+            if !write {
+                data.fill(0);
+            }
+            return Ok(data.len());
+        }
+
+        access_registers(&mut self.registers, offset, data, write)
+    }
+}
+
+impl Model for ScratchDevice {
+    fn context(&self) -> &DeviceContext {
+        &self.ctx
+    }
+}